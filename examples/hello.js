@@ -0,0 +1,5 @@
+// Simple example of a JS plugin that greets whoever is in the query
+export async function handler(request) {
+  const who = new URL(request.url).searchParams.get("who") ?? "Plugin";
+  return Response.json({ greeting: `Hello ${who}!` });
+}
@@ -0,0 +1,695 @@
+//! Binary representation of messages and answers used to talk to vlugins
+//! that live behind an ABI boundary, like a sandboxed WebAssembly module.
+//!
+//! Every field is written as a little endian `u32` length followed by its
+//! bytes, a message starts with a tag byte that tells its kind.
+//! Vlugins call back into the runtime(e.g. to message other vlugins) the same
+//! way, sending a call that the runtime answers like a message.
+pub mod ffi;
+
+use crate::{async_trait, http, Answer, Error, Frame, Message, Messenger, Params, Socket};
+use alloc::{
+    boxed::Box,
+    rc::Rc,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    convert::TryInto,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+/// Version of the message format, the runtime refuses modules built for a different one
+pub const VERSION: u32 = 1;
+
+const HTTP: u8 = 0;
+const PING: u8 = 1;
+const PONG: u8 = 1;
+const ERROR: u8 = 2;
+const EVENT: u8 = 3;
+const TICK: u8 = 4;
+const WS_CONNECT: u8 = 5;
+const WS_FRAME: u8 = 6;
+const WS_CLOSE: u8 = 7;
+const HEALTH: u8 = 8;
+
+const TEXT: u8 = 0;
+const BINARY: u8 = 1;
+
+const SEND: u8 = 0;
+const PUBLISH: u8 = 1;
+const SOCKET: u8 = 2;
+const SEND_FRAME: u8 = 3;
+const CLOSE_SOCKET: u8 = 4;
+
+/// Serializes a message to be sent to a vlugin
+pub async fn encode_message(msg: Message) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    match msg {
+        Message::Http(req) => {
+            buf.push(HTTP);
+            write_request(&mut buf, req).await?;
+        }
+        Message::Ping => buf.push(PING),
+        Message::Event { topic, payload } => {
+            buf.push(EVENT);
+            write_str(&mut buf, &topic);
+            write_bytes(
+                &mut buf,
+                &serde_json::to_vec(&payload).map_err(|_| malformed())?,
+            );
+        }
+        Message::Tick(timestamp) => {
+            buf.push(TICK);
+            buf.extend_from_slice(&timestamp.to_le_bytes());
+        }
+        Message::WsConnect { id, request } => {
+            buf.push(WS_CONNECT);
+            write_str(&mut buf, &id);
+            write_request(&mut buf, request).await?;
+        }
+        Message::WsFrame { id, frame } => {
+            buf.push(WS_FRAME);
+            write_str(&mut buf, &id);
+            write_frame(&mut buf, &frame);
+        }
+        Message::WsClose { id } => {
+            buf.push(WS_CLOSE);
+            write_str(&mut buf, &id);
+        }
+        Message::Health => buf.push(HEALTH),
+    }
+    Ok(buf)
+}
+
+/// Deserializes a message sent by the runtime
+pub fn decode_message(buf: &[u8]) -> Result<Message, Error> {
+    let mut r = Reader(buf);
+    match r.u8()? {
+        HTTP => Ok(r.request()?.into()),
+        PING => Ok(Message::Ping),
+        EVENT => Ok(Message::Event {
+            topic: r.str()?.into(),
+            payload: serde_json::from_slice(r.bytes()?).map_err(|_| malformed())?,
+        }),
+        TICK => Ok(Message::Tick(u64::from_le_bytes(
+            r.take(8)?.try_into().expect("8 bytes"),
+        ))),
+        WS_CONNECT => Ok(Message::WsConnect {
+            id: r.str()?.into(),
+            request: r.request()?,
+        }),
+        WS_FRAME => Ok(Message::WsFrame {
+            id: r.str()?.into(),
+            frame: r.frame()?,
+        }),
+        WS_CLOSE => Ok(Message::WsClose {
+            id: r.str()?.into(),
+        }),
+        HEALTH => Ok(Message::Health),
+        _ => Err(malformed()),
+    }
+}
+
+/// Serializes the outcome of handling a message, errors are sent as well
+/// so the other side can tell them apart from a successful answer
+pub async fn encode_answer(answer: Result<Answer, Error>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let answer = match answer {
+        Ok(Answer::Http(mut res)) => match res.body_bytes().await {
+            Ok(body) => {
+                buf.push(HTTP);
+                write_u16(&mut buf, res.status().into());
+                write_headers(&mut buf, res.as_ref());
+                write_bytes(&mut buf, &body);
+                return buf;
+            }
+            Err(err) => Err(err.into()),
+        },
+        answer => answer,
+    };
+    match answer {
+        Ok(Answer::WsConnect) => buf.push(WS_CONNECT),
+        Ok(Answer::WsFrame(frame)) => {
+            buf.push(WS_FRAME);
+            write_frame(&mut buf, &frame);
+        }
+        Ok(Answer::WsClose) => buf.push(WS_CLOSE),
+        Ok(_) => buf.push(PONG),
+        Err(err) => {
+            buf.push(ERROR);
+            let (status, msg) = match err {
+                Error::Http(err) => (err.status(), err.to_string()),
+                err => (http::StatusCode::InternalServerError, err.to_string()),
+            };
+            write_u16(&mut buf, status.into());
+            write_str(&mut buf, &msg);
+        }
+    }
+    buf
+}
+
+/// Deserializes the answer of a vlugin
+pub fn decode_answer(buf: &[u8]) -> Result<Answer, Error> {
+    let mut r = Reader(buf);
+    match r.u8()? {
+        HTTP => {
+            let mut res = http::Response::new(r.status()?);
+            for (name, value) in r.pairs()? {
+                res.append_header(name, value).map_err(|_| malformed())?;
+            }
+            res.set_body(r.bytes()?);
+            Ok(res.into())
+        }
+        PONG => Ok(Answer::Pong),
+        WS_CONNECT => Ok(Answer::WsConnect),
+        WS_FRAME => Ok(Answer::WsFrame(r.frame()?)),
+        WS_CLOSE => Ok(Answer::WsClose),
+        ERROR => {
+            let status = r.status()?;
+            Err(http::Error::from_str(status, String::from(r.str()?)).into())
+        }
+        _ => Err(malformed()),
+    }
+}
+
+/// Drives a future to completion on the current thread, meant for the guest
+/// side of a sandbox where there is no I/O to wait for
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = Box::pin(fut);
+    loop {
+        if let Poll::Ready(out) = Pin::as_mut(&mut fut).poll(&mut cx) {
+            return out;
+        }
+    }
+}
+
+/// What a vlugin asks the runtime through its messenger
+enum Call {
+    Send {
+        to: String,
+        msg: Box<Message>,
+    },
+    Publish {
+        topic: String,
+        payload: serde_json::Value,
+    },
+    /// Checks the socket is open
+    Socket {
+        id: String,
+    },
+    SendFrame {
+        id: String,
+        frame: Frame,
+    },
+    CloseSocket {
+        id: String,
+    },
+}
+
+async fn encode_call(call: Call) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    match call {
+        Call::Send { to, msg } => {
+            buf.push(SEND);
+            write_str(&mut buf, &to);
+            write_bytes(&mut buf, &encode_message(*msg).await?);
+        }
+        Call::Publish { topic, payload } => {
+            buf.push(PUBLISH);
+            write_str(&mut buf, &topic);
+            write_bytes(
+                &mut buf,
+                &serde_json::to_vec(&payload).map_err(|_| malformed())?,
+            );
+        }
+        Call::Socket { id } => {
+            buf.push(SOCKET);
+            write_str(&mut buf, &id);
+        }
+        Call::SendFrame { id, frame } => {
+            buf.push(SEND_FRAME);
+            write_str(&mut buf, &id);
+            write_frame(&mut buf, &frame);
+        }
+        Call::CloseSocket { id } => {
+            buf.push(CLOSE_SOCKET);
+            write_str(&mut buf, &id);
+        }
+    }
+    Ok(buf)
+}
+
+fn decode_call(buf: &[u8]) -> Result<Call, Error> {
+    let mut r = Reader(buf);
+    match r.u8()? {
+        SEND => Ok(Call::Send {
+            to: r.str()?.into(),
+            msg: Box::new(decode_message(r.bytes()?)?),
+        }),
+        PUBLISH => Ok(Call::Publish {
+            topic: r.str()?.into(),
+            payload: serde_json::from_slice(r.bytes()?).map_err(|_| malformed())?,
+        }),
+        SOCKET => Ok(Call::Socket {
+            id: r.str()?.into(),
+        }),
+        SEND_FRAME => Ok(Call::SendFrame {
+            id: r.str()?.into(),
+            frame: r.frame()?,
+        }),
+        CLOSE_SOCKET => Ok(Call::CloseSocket {
+            id: r.str()?.into(),
+        }),
+        _ => Err(malformed()),
+    }
+}
+
+/// Makes the call a vlugin sent from behind the boundary with the messenger
+/// it was linked with and serializes the answer
+pub async fn answer_call(messenger: &dyn Messenger, call: &[u8]) -> Vec<u8> {
+    let socket = |id: &str| {
+        messenger.socket(id).ok_or_else(|| {
+            Error::from(http::Error::from_str(
+                http::StatusCode::NotFound,
+                "Socket not found",
+            ))
+        })
+    };
+    let answer = match decode_call(call) {
+        Ok(Call::Send { to, msg }) => messenger.send(&to, *msg).await,
+        Ok(Call::Publish { topic, payload }) => {
+            messenger.publish(&topic, payload).await;
+            Ok(Answer::Pong)
+        }
+        Ok(Call::Socket { id }) => socket(&id).map(|_| Answer::Pong),
+        Ok(Call::SendFrame { id, frame }) => socket(&id)
+            .and_then(|socket| socket.send(frame))
+            .map(|_| Answer::Pong),
+        Ok(Call::CloseSocket { id }) => socket(&id).map(|socket| {
+            socket.close();
+            Answer::Pong
+        }),
+        Err(err) => Err(err),
+    };
+    encode_answer(answer).await
+}
+
+/// Sends a serialized call to the runtime and resolves to its serialized answer
+type CallFn = dyn Fn(Vec<u8>) -> Pin<Box<dyn Future<Output = Vec<u8>>>>;
+
+/// Messenger a vlugin behind the boundary is linked with, it passes the calls
+/// to the runtime on the other side
+#[derive(Clone)]
+pub(crate) struct HostMessenger(Rc<CallFn>);
+
+impl HostMessenger {
+    pub fn new(call: impl Fn(Vec<u8>) -> Pin<Box<dyn Future<Output = Vec<u8>>>> + 'static) -> Self {
+        HostMessenger(Rc::new(call))
+    }
+
+    async fn call(&self, call: Call) -> Result<Answer, Error> {
+        let call = encode_call(call).await?;
+        decode_answer(&(self.0)(call).await)
+    }
+}
+
+#[async_trait(?Send)]
+impl Messenger for HostMessenger {
+    async fn send(&self, to: &str, msg: Message) -> Result<Answer, Error> {
+        self.call(Call::Send {
+            to: to.into(),
+            msg: Box::new(msg),
+        })
+        .await
+    }
+
+    async fn publish(&self, topic: &str, payload: serde_json::Value) {
+        let topic = topic.into();
+        let _ = self.call(Call::Publish { topic, payload }).await;
+    }
+
+    fn socket(&self, id: &str) -> Option<Rc<dyn Socket>> {
+        // calls about sockets are answered right away
+        block_on(self.call(Call::Socket { id: id.into() })).ok()?;
+        Some(Rc::new(HostSocket {
+            messenger: self.clone(),
+            id: id.into(),
+        }))
+    }
+}
+
+struct HostSocket {
+    messenger: HostMessenger,
+    id: String,
+}
+
+impl Socket for HostSocket {
+    fn send(&self, frame: Frame) -> Result<(), Error> {
+        let id = self.id.clone();
+        block_on(self.messenger.call(Call::SendFrame { id, frame })).map(|_| ())
+    }
+
+    fn close(&self) {
+        let id = self.id.clone();
+        let _ = block_on(self.messenger.call(Call::CloseSocket { id }));
+    }
+}
+
+/// Helpers used by the exports the `vlugin` macro generates for WebAssembly
+/// modules. The host passes data by allocating a buffer in the guest memory
+/// with `valor_alloc`, calls return a buffer packed in a `u64` as `ptr << 32 | len`
+/// that the host frees with `valor_free` once it's done reading it.
+/// Calls to the runtime go the other way through the `valor.call` import.
+#[cfg(target_arch = "wasm32")]
+pub mod guest {
+    use super::*;
+    use crate::{Vlugin, VluginConfig};
+    use core::cell::RefCell;
+
+    #[link(wasm_import_module = "valor")]
+    extern "C" {
+        /// Answers a call of the vlugin with a buffer allocated with `alloc`
+        #[link_name = "call"]
+        fn host_call(ptr: u32, len: u32) -> u64;
+    }
+
+    struct Instance(RefCell<Option<Rc<dyn Vlugin>>>);
+    // WebAssembly modules run in a single thread
+    unsafe impl Sync for Instance {}
+
+    static INSTANCE: Instance = Instance(RefCell::new(None));
+
+    pub fn alloc(len: u32) -> u32 {
+        let buf = alloc::vec![0u8; len as usize].into_boxed_slice();
+        Box::into_raw(buf) as *mut u8 as u32
+    }
+
+    /// # Safety
+    /// The buffer must have been allocated with `alloc`
+    pub unsafe fn free(ptr: u32, len: u32) {
+        drop(take(ptr, len));
+    }
+
+    /// # Safety
+    /// The config buffer must have been allocated with `alloc`, it's freed afterwards
+    pub unsafe fn create<V>(ptr: u32, len: u32) -> u64
+    where
+        V: Vlugin + Default + 'static,
+    {
+        let cfg = take(ptr, len);
+        let cfg = (!cfg.is_empty())
+            .then(|| serde_json::from_slice::<VluginConfig>(&cfg).ok())
+            .flatten();
+        let answer = block_on(V::create(cfg)).map(|mut vlugin| {
+            vlugin.link(Rc::new(HostMessenger::new(|call| {
+                let out = unsafe { host_call(call.as_ptr() as u32, call.len() as u32) };
+                let answer = unsafe { take((out >> 32) as u32, out as u32) };
+                Box::pin(async move { answer.into_vec() })
+            })));
+            INSTANCE.0.replace(Some(Rc::new(vlugin)));
+            Answer::Pong
+        });
+        into_raw(block_on(encode_answer(answer)))
+    }
+
+    /// # Safety
+    /// The message buffer must have been allocated with `alloc`, it's freed afterwards
+    pub unsafe fn on_msg(ptr: u32, len: u32) -> u64 {
+        let msg = take(ptr, len);
+        let answer = block_on(async {
+            let msg = decode_message(&msg)?;
+            let vlugin = INSTANCE.0.borrow().clone().ok_or(Error::NotSupported)?;
+            vlugin.on_msg(msg).await
+        });
+        into_raw(block_on(encode_answer(answer)))
+    }
+
+    /// # Safety
+    /// The buffer must have been allocated with `alloc`, it's freed afterwards
+    pub unsafe fn destroy(ptr: u32, len: u32) -> u64 {
+        drop(take(ptr, len));
+        let answer = block_on(async {
+            let vlugin = INSTANCE.0.borrow_mut().take().ok_or(Error::NotSupported)?;
+            vlugin.on_destroy().await.map(|_| Answer::Pong)
+        });
+        into_raw(block_on(encode_answer(answer)))
+    }
+
+    unsafe fn take(ptr: u32, len: u32) -> Box<[u8]> {
+        Box::from_raw(core::ptr::slice_from_raw_parts_mut(
+            ptr as *mut u8,
+            len as usize,
+        ))
+    }
+
+    fn into_raw(buf: Vec<u8>) -> u64 {
+        let buf = buf.into_boxed_slice();
+        let len = buf.len() as u64;
+        let ptr = Box::into_raw(buf) as *mut u8 as u64;
+        ptr << 32 | len
+    }
+}
+
+fn malformed() -> Error {
+    http::Error::from_str(http::StatusCode::InternalServerError, "Malformed message").into()
+}
+
+fn write_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_bytes(buf, s.as_bytes());
+}
+
+async fn write_request(buf: &mut Vec<u8>, mut req: http::Request) -> Result<(), Error> {
+    write_str(buf, req.method().as_ref());
+    write_str(buf, req.url().as_str());
+    write_headers(buf, req.as_ref());
+    write_bytes(buf, &req.body_bytes().await?);
+    write_pairs(
+        buf,
+        req.ext().get::<Params>().into_iter().flat_map(Params::iter),
+    );
+    Ok(())
+}
+
+fn write_frame(buf: &mut Vec<u8>, frame: &Frame) {
+    match frame {
+        Frame::Text(text) => {
+            buf.push(TEXT);
+            write_str(buf, text);
+        }
+        Frame::Binary(data) => {
+            buf.push(BINARY);
+            write_bytes(buf, data);
+        }
+    }
+}
+
+fn write_headers(buf: &mut Vec<u8>, headers: &http::Headers) {
+    let headers = headers
+        .iter()
+        .flat_map(|(name, values)| values.iter().map(move |v| (name.as_str(), v.as_str())));
+    write_pairs(buf, headers);
+}
+
+fn write_pairs<'a>(buf: &mut Vec<u8>, pairs: impl Iterator<Item = (&'a str, &'a str)>) {
+    let pairs = pairs.collect::<Vec<_>>();
+    buf.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+    for (name, value) in pairs {
+        write_str(buf, name);
+        write_str(buf, value);
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < n {
+            return Err(malformed());
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+
+    fn status(&mut self) -> Result<http::StatusCode, Error> {
+        let status = u16::from_le_bytes(self.take(2)?.try_into().expect("2 bytes"));
+        status.try_into().map_err(|_| malformed())
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        core::str::from_utf8(self.bytes()?).map_err(|_| malformed())
+    }
+
+    fn pairs(&mut self) -> Result<Vec<(&'a str, &'a str)>, Error> {
+        (0..self.u32()?)
+            .map(|_| Ok((self.str()?, self.str()?)))
+            .collect()
+    }
+
+    fn request(&mut self) -> Result<http::Request, Error> {
+        let method = self.str()?.parse().map_err(|_| malformed())?;
+        let url = self.str()?;
+        let mut req = http::Request::new(method, url);
+        for (name, value) in self.pairs()? {
+            req.append_header(name, value).map_err(|_| malformed())?;
+        }
+        req.set_body(self.bytes()?);
+        let params = self.pairs()?.into_iter().collect::<Params>();
+        if !params.is_empty() {
+            req.ext_mut().insert(params);
+        }
+        Ok(req)
+    }
+
+    fn frame(&mut self) -> Result<Frame, Error> {
+        match self.u8()? {
+            TEXT => Ok(Frame::Text(self.str()?.into())),
+            BINARY => Ok(Frame::Binary(self.bytes()?.into())),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::TryFrom;
+
+    #[async_std::test]
+    async fn request_roundtrip() -> Result<(), Error> {
+        let mut req = http::Request::new(http::Method::Post, "http://example.com/foo?bar=1");
+        req.insert_header("x-foo", "foo");
+        req.set_body("hello");
+        req.ext_mut()
+            .insert(vec![("id", "1")].into_iter().collect::<Params>());
+        let buf = encode_message(req.into()).await?;
+
+        let mut req =
+            http::Request::try_from(decode_message(&buf)?).map_err(|_| Error::NotSupported)?;
+        assert_eq!(req.method(), http::Method::Post);
+        assert_eq!(req.url().as_str(), "http://example.com/foo?bar=1");
+        assert_eq!(req.header("x-foo").unwrap(), "foo");
+        assert_eq!(req.body_string().await?, "hello");
+        assert_eq!(req.ext().get::<Params>().unwrap().get("id"), Some("1"));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn event_roundtrip() -> Result<(), Error> {
+        let payload = serde_json::json!({ "name": "foo" });
+        let event = Message::Event {
+            topic: "foo.loaded".into(),
+            payload: payload.clone(),
+        };
+        match decode_message(&encode_message(event).await?)? {
+            Message::Event { topic, payload: p } => {
+                assert_eq!(topic, "foo.loaded");
+                assert_eq!(p, payload);
+            }
+            _ => panic!("expected an event"),
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn answer_roundtrip() -> Result<(), Error> {
+        let mut res = http::Response::new(http::StatusCode::Accepted);
+        res.insert_header("x-foo", "foo");
+        res.set_body("hello");
+        let buf = encode_answer(Ok(res.into())).await;
+
+        let mut res: http::Response = decode_answer(&buf)?.into();
+        assert_eq!(res.status(), http::StatusCode::Accepted);
+        assert_eq!(res.header("x-foo").unwrap(), "foo");
+        assert_eq!(res.body_string().await?, "hello");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn error_answer_keeps_status() {
+        let err = http::Error::from_str(http::StatusCode::BadRequest, "nope");
+        let buf = encode_answer(Err(err.into())).await;
+        match decode_answer(&buf) {
+            Err(Error::Http(err)) => assert_eq!(err.status(), http::StatusCode::BadRequest),
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[async_std::test]
+    async fn socket_roundtrip() -> Result<(), Error> {
+        let req = http::Request::get("http://example.com/chat");
+        let connect = Message::WsConnect {
+            id: "1".into(),
+            request: req,
+        };
+        match decode_message(&encode_message(connect).await?)? {
+            Message::WsConnect { id, request } => {
+                assert_eq!(id, "1");
+                assert_eq!(request.url().path(), "/chat");
+            }
+            _ => panic!("expected a connection"),
+        }
+
+        let frame = Message::WsFrame {
+            id: "1".into(),
+            frame: Frame::Binary(vec![1, 2, 3]),
+        };
+        assert!(matches!(
+            decode_message(&encode_message(frame).await?)?,
+            Message::WsFrame { frame: Frame::Binary(data), .. } if data == [1, 2, 3]
+        ));
+
+        let buf = encode_answer(Ok(Frame::from("hi").into())).await;
+        assert!(matches!(decode_answer(&buf)?, Answer::WsFrame(Frame::Text(t)) if t == "hi"));
+        let buf = encode_answer(Ok(Answer::WsConnect)).await;
+        assert!(matches!(decode_answer(&buf)?, Answer::WsConnect));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn tick_roundtrip() -> Result<(), Error> {
+        let buf = encode_message(Message::Tick(1_614_591_000)).await?;
+        assert!(matches!(
+            decode_message(&buf)?,
+            Message::Tick(1_614_591_000)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,872 @@
+//! FFI-safe interface between the runtime and natively compiled vlugins.
+//!
+//! Nothing but `repr(C)` types and plain bytes cross the library boundary so
+//! plugins and the runtime don't need to be built with the same compiler.
+//! Futures are driven by the host through a poll function and a waker that is
+//! itself a table of `extern "C"` callbacks. Each side frees what it allocated.
+//! Response bodies of unknown length are streamed, the host reads them a chunk
+//! at a time through an `FfiBody` instead of getting them with the answer.
+//! Plugins call back into the runtime through the `FfiMessenger` they are linked with.
+//! Panics can't unwind across the boundary, with the `std` feature the callbacks catch them
+//! and answer with an error instead of aborting the process.
+use super::{answer_call, decode_answer, decode_message, encode_answer, HostMessenger};
+use crate::{async_trait, http, Answer, Context, Error, Message, Messenger, Vlugin, VluginConfig};
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+use core::{
+    ffi::c_void,
+    future::Future,
+    pin::Pin,
+    ptr, slice,
+    task::{self, Poll, RawWaker, RawWakerVTable, Waker},
+};
+use futures_lite::{io, ready, AsyncBufRead, AsyncRead, AsyncReadExt};
+
+/// Version of the interface, the runtime refuses plugins built for a different one
+pub const ABI_VERSION: u32 = 1;
+
+/// Version of `valor_core` on each side of the boundary
+pub const CORE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Information a native plugin exports about itself
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Metadata {
+    pub core_version: Str,
+    pub rustc_version: Str,
+    pub name: Str,
+    pub version: Str,
+    /// Routes declared by the plugin separated by new lines
+    pub routes: Str,
+}
+
+impl Metadata {
+    pub const fn new(
+        rustc_version: &'static str,
+        name: &'static str,
+        version: &'static str,
+        routes: &'static str,
+    ) -> Self {
+        Metadata {
+            core_version: Str::new(CORE_VERSION),
+            rustc_version: Str::new(rustc_version),
+            name: Str::new(name),
+            version: Str::new(version),
+            routes: Str::new(routes),
+        }
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.as_str().lines().filter(|r| !r.is_empty())
+    }
+
+    /// Plugins built with a `valor_core` that isn't semver compatible can't be used
+    pub fn is_compatible(&self) -> bool {
+        fn major_minor(v: &str) -> (&str, &str) {
+            let mut parts = v.split(&['.', '-'][..]);
+            (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+        }
+        match (
+            major_minor(self.core_version.as_str()),
+            major_minor(CORE_VERSION),
+        ) {
+            (("0", minor), ("0", own_minor)) => minor == own_minor,
+            ((major, _), (own_major, _)) => major == own_major,
+        }
+    }
+}
+
+/// A static string shared across the boundary, it's only valid
+/// for as long as the library that exported it stays loaded
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Str {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Str {
+    pub const fn new(s: &'static str) -> Self {
+        Str {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        let bytes = unsafe { slice::from_raw_parts(self.ptr, self.len) };
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+}
+
+/// Functions a native plugin exports to create and talk to its vlugin
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VluginVTable {
+    pub new: extern "C" fn() -> *mut c_void,
+    pub on_create: extern "C" fn(*mut c_void, *const u8, usize) -> FfiFuture,
+    pub on_msg: extern "C" fn(*const c_void, *const u8, usize, *mut FfiBody) -> FfiFuture,
+    pub on_destroy: extern "C" fn(*const c_void) -> FfiFuture,
+    pub link: extern "C" fn(*mut c_void, FfiMessenger),
+    pub drop: extern "C" fn(*mut c_void),
+}
+
+/// Builds the table of functions for the vlugin `V`, used by the `vlugin` macro
+pub fn vtable<V>() -> VluginVTable
+where
+    V: Vlugin + Default + 'static,
+{
+    VluginVTable {
+        new: new::<V>,
+        on_create: on_create::<V>,
+        on_msg: on_msg::<V>,
+        on_destroy: on_destroy::<V>,
+        link: link::<V>,
+        drop: drop_vlugin::<V>,
+    }
+}
+
+/// Runs the body of an `extern "C"` callback, a panic is caught and
+/// the fallback is returned in its place
+#[cfg(feature = "std")]
+fn guard<T>(f: impl FnOnce() -> T, fallback: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|_| fallback())
+}
+
+#[cfg(not(feature = "std"))]
+fn guard<T>(f: impl FnOnce() -> T, _fallback: impl FnOnce() -> T) -> T {
+    f()
+}
+
+/// Encoded answer of a callback that panicked
+fn panicked() -> Vec<u8> {
+    let err = http::Error::from_str(http::StatusCode::InternalServerError, "Plugin panicked");
+    super::block_on(encode_answer(Err(err.into())))
+}
+
+extern "C" fn new<V: Vlugin + Default>() -> *mut c_void {
+    guard(
+        || Box::into_raw(Box::new(V::default())) as *mut c_void,
+        ptr::null_mut,
+    )
+}
+
+extern "C" fn on_create<V: Vlugin + 'static>(
+    vlugin: *mut c_void,
+    cfg: *const u8,
+    len: usize,
+) -> FfiFuture {
+    // the runtime doesn't use the instance until it's created
+    let vlugin = unsafe { &mut *(vlugin as *mut V) };
+    let cfg = unsafe { slice::from_raw_parts(cfg, len) };
+    guard(
+        || {
+            let cfg = (!cfg.is_empty())
+                .then(|| serde_json::from_slice::<VluginConfig>(cfg).ok())
+                .flatten();
+            FfiFuture::new(async move {
+                if let Some(cfg) = cfg {
+                    vlugin.context_mut().with_config(cfg);
+                }
+                encode_answer(vlugin.on_create().await.map(|_| Answer::Pong)).await
+            })
+        },
+        FfiFuture::panicked,
+    )
+}
+
+extern "C" fn on_msg<V: Vlugin + 'static>(
+    vlugin: *const c_void,
+    msg: *const u8,
+    len: usize,
+    body: *mut FfiBody,
+) -> FfiFuture {
+    // the runtime keeps the instance alive while there are messages in flight
+    let vlugin = unsafe { &*(vlugin as *const V) };
+    let msg = unsafe { slice::from_raw_parts(msg, len) };
+    guard(
+        || {
+            let msg = decode_message(msg);
+            FfiFuture::new(async move {
+                let answer = match msg {
+                    Ok(msg) => vlugin.on_msg(msg).await,
+                    Err(err) => Err(err),
+                };
+                let answer = match answer {
+                    Ok(Answer::Http(mut res)) if res.len().is_none() => {
+                        // the host keeps the body it passed around until the answer is ready
+                        unsafe { *body = FfiBody::new(res.take_body()) };
+                        Ok(Answer::Http(res))
+                    }
+                    answer => answer,
+                };
+                encode_answer(answer).await
+            })
+        },
+        FfiFuture::panicked,
+    )
+}
+
+extern "C" fn on_destroy<V: Vlugin + 'static>(vlugin: *const c_void) -> FfiFuture {
+    let vlugin = unsafe { &*(vlugin as *const V) };
+    FfiFuture::new(
+        async move { encode_answer(vlugin.on_destroy().await.map(|_| Answer::Pong)).await },
+    )
+}
+
+extern "C" fn link<V: Vlugin + 'static>(vlugin: *mut c_void, messenger: FfiMessenger) {
+    // the runtime links the instance before it gets any message
+    let vlugin = unsafe { &mut *(vlugin as *mut V) };
+    let messenger = Rc::new(messenger);
+    guard(
+        || {
+            vlugin.link(Rc::new(HostMessenger::new(move |call| {
+                Box::pin((messenger.call)(
+                    messenger.messenger,
+                    call.as_ptr(),
+                    call.len(),
+                ))
+            })))
+        },
+        || (),
+    )
+}
+
+extern "C" fn drop_vlugin<V>(vlugin: *mut c_void) {
+    guard(|| drop(unsafe { Box::from_raw(vlugin as *mut V) }), || ());
+}
+
+/// Bytes that are freed by the side that allocated them
+#[repr(C)]
+pub struct Buffer {
+    ptr: *mut u8,
+    len: usize,
+    free: extern "C" fn(*mut u8, usize),
+}
+
+impl Buffer {
+    /// Copies the bytes and gives the buffer back to its owner
+    pub fn into_vec(self) -> Vec<u8> {
+        let bytes = unsafe { slice::from_raw_parts(self.ptr, self.len) }.to_vec();
+        (self.free)(self.ptr, self.len);
+        bytes
+    }
+}
+
+impl From<Vec<u8>> for Buffer {
+    fn from(bytes: Vec<u8>) -> Self {
+        extern "C" fn free(ptr: *mut u8, len: usize) {
+            drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len)) });
+        }
+        let bytes = bytes.into_boxed_slice();
+        Buffer {
+            len: bytes.len(),
+            ptr: Box::into_raw(bytes) as *mut u8,
+            free,
+        }
+    }
+}
+
+/// Result of polling a future on the other side of the boundary,
+/// the output is only meaningful when it's ready
+#[repr(C)]
+pub struct FfiPoll {
+    ready: bool,
+    output: Buffer,
+}
+
+/// A future that resolves to an encoded answer
+#[repr(C)]
+pub struct FfiFuture {
+    state: *mut c_void,
+    poll: extern "C" fn(*mut c_void, FfiWaker) -> FfiPoll,
+    drop: extern "C" fn(*mut c_void),
+}
+
+struct Task {
+    fut: Pin<Box<dyn Future<Output = Vec<u8>>>>,
+    // output of the future when polling it panics
+    fallback: fn() -> Vec<u8>,
+}
+
+impl FfiFuture {
+    /// A future that resolves to an encoded answer, an error one if it panics
+    fn new(fut: impl Future<Output = Vec<u8>> + 'static) -> Self {
+        Self::with_fallback(fut, panicked)
+    }
+
+    /// A future that is ready with the error answer of a panicked callback
+    fn panicked() -> Self {
+        Self::new(async { panicked() })
+    }
+
+    fn with_fallback(
+        fut: impl Future<Output = Vec<u8>> + 'static,
+        fallback: fn() -> Vec<u8>,
+    ) -> Self {
+        extern "C" fn poll(task: *mut c_void, waker: FfiWaker) -> FfiPoll {
+            let task = unsafe { &mut *(task as *mut Task) };
+            let waker = waker.into_waker();
+            let mut cx = task::Context::from_waker(&waker);
+            let fallback = task.fallback;
+            match guard(
+                || task.fut.as_mut().poll(&mut cx),
+                || Poll::Ready(fallback()),
+            ) {
+                Poll::Ready(output) => FfiPoll {
+                    ready: true,
+                    output: output.into(),
+                },
+                Poll::Pending => FfiPoll {
+                    ready: false,
+                    output: Vec::new().into(),
+                },
+            }
+        }
+        extern "C" fn drop_task(task: *mut c_void) {
+            guard(|| drop(unsafe { Box::from_raw(task as *mut Task) }), || ());
+        }
+        let task = Box::new(Task {
+            fut: Box::pin(fut),
+            fallback,
+        });
+        FfiFuture {
+            state: Box::into_raw(task) as *mut c_void,
+            poll,
+            drop: drop_task,
+        }
+    }
+}
+
+impl Future for FfiFuture {
+    type Output = Vec<u8>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let FfiPoll { ready, output } = (self.poll)(self.state, cx.waker().into());
+        let output = output.into_vec();
+        if ready {
+            Poll::Ready(output)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for FfiFuture {
+    fn drop(&mut self) {
+        (self.drop)(self.state)
+    }
+}
+
+/// Messenger of the runtime passed to the plugin, its calls are answered
+/// by the runtime and it's dropped by the plugin when it no longer needs it
+#[repr(C)]
+pub struct FfiMessenger {
+    messenger: *mut c_void,
+    call: extern "C" fn(*const c_void, *const u8, usize) -> FfiFuture,
+    drop: extern "C" fn(*mut c_void),
+}
+
+impl FfiMessenger {
+    fn new(messenger: Rc<dyn Messenger>) -> Self {
+        extern "C" fn call(messenger: *const c_void, call: *const u8, len: usize) -> FfiFuture {
+            let messenger = unsafe { &*(messenger as *const Rc<dyn Messenger>) }.clone();
+            let call = unsafe { slice::from_raw_parts(call, len) }.to_vec();
+            FfiFuture::new(async move { answer_call(&*messenger, &call).await })
+        }
+        extern "C" fn drop_messenger(messenger: *mut c_void) {
+            drop(unsafe { Box::from_raw(messenger as *mut Rc<dyn Messenger>) });
+        }
+        FfiMessenger {
+            messenger: Box::into_raw(Box::new(messenger)) as *mut c_void,
+            call,
+            drop: drop_messenger,
+        }
+    }
+}
+
+impl Drop for FfiMessenger {
+    fn drop(&mut self) {
+        (self.drop)(self.messenger)
+    }
+}
+
+/// Body of a response read a chunk at a time from the other side of
+/// the boundary, an empty chunk means the body is over
+#[repr(C)]
+pub struct FfiBody {
+    body: *mut c_void,
+    read: extern "C" fn(*mut c_void) -> FfiFuture,
+    drop: extern "C" fn(*mut c_void),
+}
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+impl FfiBody {
+    /// A body with nothing to read, the answer comes with its body if it stays empty
+    fn empty() -> Self {
+        extern "C" fn read(_: *mut c_void) -> FfiFuture {
+            FfiFuture::new(async { Vec::new() })
+        }
+        extern "C" fn drop_body(_: *mut c_void) {}
+        FfiBody {
+            body: ptr::null_mut(),
+            read,
+            drop: drop_body,
+        }
+    }
+
+    fn new(body: http::Body) -> Self {
+        extern "C" fn read(body: *mut c_void) -> FfiFuture {
+            // the host waits for a chunk before reading the next one or dropping the body
+            let body = unsafe { &mut *(body as *mut http::Body) };
+            // errors or panics reading the body can only end it
+            FfiFuture::with_fallback(
+                async move {
+                    let mut chunk = alloc::vec![0; CHUNK_SIZE];
+                    let n = body.read(&mut chunk).await.unwrap_or(0);
+                    chunk.truncate(n);
+                    chunk
+                },
+                Vec::new,
+            )
+        }
+        extern "C" fn drop_body(body: *mut c_void) {
+            guard(
+                || drop(unsafe { Box::from_raw(body as *mut http::Body) }),
+                || (),
+            );
+        }
+        FfiBody {
+            body: Box::into_raw(Box::new(body)) as *mut c_void,
+            read,
+            drop: drop_body,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.body.is_null()
+    }
+}
+
+impl Drop for FfiBody {
+    fn drop(&mut self) {
+        (self.drop)(self.body)
+    }
+}
+
+/// Streamed body of an answer that reads the `FfiBody` given by the plugin
+struct ForeignBody {
+    // NOTE fields are dropped in order, a pending read has to go before its body
+    reading: Option<FfiFuture>,
+    body: FfiBody,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+// the body only points to an `http::Body` and futures reading it that are `Send` and `Sync`
+unsafe impl Send for ForeignBody {}
+unsafe impl Sync for ForeignBody {}
+
+impl AsyncRead for ForeignBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let chunk = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncBufRead for ForeignBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        while this.pos == this.chunk.len() && !this.body.is_empty() {
+            let (read, body) = (this.body.read, this.body.body);
+            let reading = this.reading.get_or_insert_with(|| read(body));
+            let chunk = ready!(Pin::new(reading).poll(cx));
+            this.reading = None;
+            if chunk.is_empty() {
+                // let the plugin free the body as soon as it's over
+                this.body = FfiBody::empty();
+            }
+            this.chunk = chunk;
+            this.pos = 0;
+        }
+        Poll::Ready(Ok(&this.chunk[this.pos..]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// A waker that can be passed to the other side of the boundary
+#[repr(C)]
+pub struct FfiWaker {
+    data: *const c_void,
+    wake: extern "C" fn(*const c_void),
+    clone: extern "C" fn(*const c_void) -> FfiWaker,
+    drop: extern "C" fn(*const c_void),
+}
+
+impl From<&Waker> for FfiWaker {
+    fn from(waker: &Waker) -> Self {
+        extern "C" fn wake(waker: *const c_void) {
+            unsafe { &*(waker as *const Waker) }.wake_by_ref()
+        }
+        extern "C" fn clone(waker: *const c_void) -> FfiWaker {
+            unsafe { &*(waker as *const Waker) }.into()
+        }
+        extern "C" fn drop_waker(waker: *const c_void) {
+            drop(unsafe { Box::from_raw(waker as *mut Waker) });
+        }
+        FfiWaker {
+            data: Box::into_raw(Box::new(waker.clone())) as *const c_void,
+            wake,
+            clone,
+            drop: drop_waker,
+        }
+    }
+}
+
+impl FfiWaker {
+    fn into_waker(self) -> Waker {
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+        unsafe fn raw_waker(waker: FfiWaker) -> RawWaker {
+            RawWaker::new(Box::into_raw(Box::new(waker)) as *const (), &VTABLE)
+        }
+        unsafe fn clone(waker: *const ()) -> RawWaker {
+            let waker = &*(waker as *const FfiWaker);
+            raw_waker((waker.clone)(waker.data))
+        }
+        unsafe fn wake(waker: *const ()) {
+            wake_by_ref(waker);
+            drop_waker(waker);
+        }
+        unsafe fn wake_by_ref(waker: *const ()) {
+            let waker = &*(waker as *const FfiWaker);
+            (waker.wake)(waker.data)
+        }
+        unsafe fn drop_waker(waker: *const ()) {
+            let waker = Box::from_raw(waker as *mut FfiWaker);
+            (waker.drop)(waker.data)
+        }
+        unsafe { Waker::from_raw(raw_waker(self)) }
+    }
+}
+
+/// Vlugin living on the other side of the boundary, used by the runtime
+/// to talk to the instance created with the plugin's `VluginVTable`
+pub struct ForeignVlugin {
+    vlugin: *mut c_void,
+    vtable: VluginVTable,
+    cx: Context,
+}
+
+impl ForeignVlugin {
+    /// # Safety
+    /// The functions of the table must stay valid for as long as the vlugin lives,
+    /// e.g. the library that exports them must remain loaded
+    pub async unsafe fn create(
+        vtable: VluginVTable,
+        cfg: Option<VluginConfig>,
+    ) -> Result<Self, Error> {
+        let vlugin = (vtable.new)();
+        if vlugin.is_null() {
+            let err = http::Error::from_str(
+                http::StatusCode::InternalServerError,
+                "Plugin panicked creating its vlugin",
+            );
+            return Err(err.into());
+        }
+        let vlugin = ForeignVlugin {
+            vlugin,
+            vtable,
+            cx: Context::default(),
+        };
+        let cfg = cfg
+            .map(|cfg| serde_json::to_vec(&cfg).expect("valid json"))
+            .unwrap_or_default();
+        let created = (vtable.on_create)(vlugin.vlugin, cfg.as_ptr(), cfg.len()).await;
+        decode_answer(&created)?;
+        Ok(vlugin)
+    }
+}
+
+#[async_trait(?Send)]
+impl Vlugin for ForeignVlugin {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        let msg = super::encode_message(msg).await?;
+        // filled by the plugin when the body of the answer is streamed
+        let mut body = FfiBody::empty();
+        let answer = (self.vtable.on_msg)(self.vlugin, msg.as_ptr(), msg.len(), &mut body).await;
+        match decode_answer(&answer)? {
+            Answer::Http(mut res) if !body.is_empty() => {
+                let body = ForeignBody {
+                    reading: None,
+                    body,
+                    chunk: Vec::new(),
+                    pos: 0,
+                };
+                res.set_body(http::Body::from_reader(body, None));
+                Ok(res.into())
+            }
+            answer => Ok(answer),
+        }
+    }
+
+    async fn on_destroy(&self) -> Result<(), Error> {
+        decode_answer(&(self.vtable.on_destroy)(self.vlugin).await)?;
+        Ok(())
+    }
+
+    fn link(&mut self, messenger: Rc<dyn Messenger>) {
+        (self.vtable.link)(self.vlugin, FfiMessenger::new(messenger))
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        &mut self.cx
+    }
+    fn context(&self) -> &Context {
+        &self.cx
+    }
+}
+
+impl Drop for ForeignVlugin {
+    fn drop(&mut self) {
+        (self.vtable.drop)(self.vlugin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http;
+    use core::convert::TryFrom;
+
+    #[async_std::test]
+    async fn talk_to_vlugin_through_vtable() -> Result<(), Error> {
+        #[derive(Default)]
+        struct Echo(Context);
+
+        #[async_trait(?Send)]
+        impl Vlugin for Echo {
+            async fn on_create(&mut self) -> Result<(), Error> {
+                let greeting = self.0.raw_config().and_then(|c| c.as_str()).unwrap_or("");
+                self.0.set(alloc::string::String::from(greeting));
+                Ok(())
+            }
+
+            async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+                let mut req = http::Request::try_from(msg).map_err(|_| Error::NotSupported)?;
+                async_std::task::yield_now().await;
+                let body = req.body_string().await?;
+                let greeting = self.0.get::<alloc::string::String>();
+                Ok(http::Body::from(alloc::format!("{} {}", greeting, body)).into())
+            }
+
+            fn context_mut(&mut self) -> &mut Context {
+                &mut self.0
+            }
+            fn context(&self) -> &Context {
+                &self.0
+            }
+        }
+
+        let vlugin =
+            unsafe { ForeignVlugin::create(vtable::<Echo>(), Some("hello".into())) }.await?;
+        let mut req = http::Request::new(http::Method::Post, "http://example.com");
+        req.set_body("world");
+        let mut res: http::Response = vlugin.on_msg(req.into()).await?.into();
+        assert_eq!(res.body_string().await?, "hello world");
+        vlugin.on_destroy().await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn stream_bodies_of_unknown_length() -> Result<(), Error> {
+        #[derive(Default)]
+        struct Streaming(Context);
+
+        #[async_trait(?Send)]
+        impl Vlugin for Streaming {
+            async fn on_msg(&self, _msg: Message) -> Result<Answer, Error> {
+                let body = futures_lite::io::Cursor::new("a".repeat(3 * CHUNK_SIZE / 2));
+                Ok(http::Body::from_reader(body, None).into())
+            }
+
+            fn context_mut(&mut self) -> &mut Context {
+                &mut self.0
+            }
+            fn context(&self) -> &Context {
+                &self.0
+            }
+        }
+
+        let vlugin = unsafe { ForeignVlugin::create(vtable::<Streaming>(), None) }.await?;
+        let req = http::Request::new(http::Method::Get, "http://example.com");
+        let mut res: http::Response = vlugin.on_msg(req.into()).await?.into();
+        assert_eq!(res.len(), None);
+        assert_eq!(res.body_string().await?, "a".repeat(3 * CHUNK_SIZE / 2));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn panics_dont_cross_the_boundary() -> Result<(), Error> {
+        #[derive(Default)]
+        struct Panicking(Context);
+
+        #[async_trait(?Send)]
+        impl Vlugin for Panicking {
+            async fn on_msg(&self, _msg: Message) -> Result<Answer, Error> {
+                async_std::task::yield_now().await;
+                panic!("oops")
+            }
+
+            fn context_mut(&mut self) -> &mut Context {
+                &mut self.0
+            }
+            fn context(&self) -> &Context {
+                &self.0
+            }
+        }
+
+        struct Broken;
+
+        impl Default for Broken {
+            fn default() -> Self {
+                panic!("oops")
+            }
+        }
+
+        #[async_trait(?Send)]
+        impl Vlugin for Broken {
+            async fn on_msg(&self, _msg: Message) -> Result<Answer, Error> {
+                Ok(Answer::Pong)
+            }
+
+            fn context_mut(&mut self) -> &mut Context {
+                unreachable!()
+            }
+            fn context(&self) -> &Context {
+                unreachable!()
+            }
+        }
+
+        let vlugin = unsafe { ForeignVlugin::create(vtable::<Panicking>(), None) }.await?;
+        let req = http::Request::new(http::Method::Get, "http://example.com");
+        match vlugin.on_msg(req.into()).await {
+            Err(Error::Http(err)) => assert_eq!(err.status(), 500),
+            _ => panic!("expected an error"),
+        }
+        assert!(unsafe { ForeignVlugin::create(vtable::<Broken>(), None) }
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn foreign_vlugins_talk_to_the_runtime() -> Result<(), Error> {
+        #[derive(Default)]
+        struct Sender(Context);
+
+        #[async_trait(?Send)]
+        impl Vlugin for Sender {
+            async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+                let req = http::Request::try_from(msg).map_err(|_| Error::NotSupported)?;
+                let path = req.url().path().to_string();
+                self.0.publish("visits", path.into()).await?;
+                self.0.send_frame("1", "hi")?;
+                assert!(self.0.send_frame("2", "hi").is_err());
+                self.0.send("other", req).await
+            }
+
+            fn context_mut(&mut self) -> &mut Context {
+                &mut self.0
+            }
+            fn context(&self) -> &Context {
+                &self.0
+            }
+        }
+
+        type Calls = Rc<core::cell::RefCell<Vec<String>>>;
+
+        struct Recorder(Calls);
+
+        #[async_trait(?Send)]
+        impl Messenger for Recorder {
+            async fn send(&self, to: &str, msg: Message) -> Result<Answer, Error> {
+                let req = http::Request::try_from(msg).map_err(|_| Error::NotSupported)?;
+                let res = alloc::format!("{} got {}", to, req.url().path());
+                Ok(http::Body::from(res).into())
+            }
+            async fn publish(&self, topic: &str, payload: serde_json::Value) {
+                let call = alloc::format!("publish {} {}", topic, payload);
+                self.0.borrow_mut().push(call);
+            }
+            fn socket(&self, id: &str) -> Option<Rc<dyn crate::Socket>> {
+                (id == "1").then(|| Rc::new(Recorder(self.0.clone())) as Rc<dyn crate::Socket>)
+            }
+        }
+
+        impl crate::Socket for Recorder {
+            fn send(&self, frame: crate::Frame) -> Result<(), Error> {
+                self.0
+                    .borrow_mut()
+                    .push(alloc::format!("frame {:?}", frame));
+                Ok(())
+            }
+            fn close(&self) {}
+        }
+
+        let calls = Calls::default();
+        let mut vlugin = unsafe { ForeignVlugin::create(vtable::<Sender>(), None) }.await?;
+        vlugin.link(Rc::new(Recorder(calls.clone())));
+        let req = http::Request::new(http::Method::Get, "http://valor/hello");
+        let mut res: http::Response = vlugin.on_msg(req.into()).await?.into();
+        assert_eq!(res.body_string().await?, "other got /hello");
+        assert_eq!(
+            *calls.borrow(),
+            ["publish visits \"/hello\"", "frame Text(\"hi\")"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_core_version_compatibility() {
+        let mut meta = Metadata::new("rustc", "foo", "1.0.0", "/foo\n/bar/*\n");
+        assert!(meta.is_compatible());
+        assert_eq!(meta.routes().collect::<Vec<_>>(), ["/foo", "/bar/*"]);
+        meta.core_version = Str::new("999.0.0");
+        assert!(!meta.is_compatible());
+    }
+
+    #[async_std::test]
+    async fn errors_cross_the_boundary() {
+        #[derive(Default)]
+        struct Failing;
+
+        #[async_trait(?Send)]
+        impl Vlugin for Failing {
+            async fn on_create(&mut self) -> Result<(), Error> {
+                Err(Error::NotSupported)
+            }
+            async fn on_msg(&self, _msg: Message) -> Result<Answer, Error> {
+                unreachable!()
+            }
+            fn context_mut(&mut self) -> &mut Context {
+                unreachable!()
+            }
+            fn context(&self) -> &Context {
+                unreachable!()
+            }
+        }
+
+        let res = unsafe { ForeignVlugin::create(vtable::<Failing>(), None) }.await;
+        assert!(res.is_err());
+    }
+}
@@ -10,10 +10,14 @@
 extern crate alloc;
 extern crate core;
 
+#[cfg(feature = "abi")]
+pub mod abi;
 #[cfg(feature = "proxy")]
 mod proxy;
 #[cfg(feature = "runtime")]
 pub mod runtime;
+#[cfg(feature = "sse")]
+pub mod sse;
 #[cfg(feature = "util")]
 mod util;
 mod vlugin;
@@ -1,5 +1,6 @@
-use crate::{async_trait, http, Answer, Context, Error, Message, Vlugin};
+use crate::{async_trait, http, Answer, Context, Error, Message, Messenger, Vlugin};
 use alloc::boxed::Box;
+use alloc::rc::Rc;
 use alloc::string::String;
 use core::convert::TryFrom;
 #[cfg(target_arch = "wasm32")]
@@ -7,7 +8,7 @@ use http_client::{h1::wasm::WasmClient as Client, HttpClient};
 #[cfg(not(target_arch = "wasm32"))]
 use http_client::{h1::H1Client as Client, HttpClient};
 
-struct Proxy {
+pub(crate) struct Proxy {
     client: Client,
     server: http::Url,
 }
@@ -28,7 +29,7 @@ impl Vlugin for Proxy {
     async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
         let mut req = match msg {
             Message::Http(req) => req,
-            Message::Ping => return Err(Error::NotSupported),
+            _ => return Err(Error::NotSupported),
         };
 
         let url = req.url();
@@ -48,6 +49,8 @@ impl Vlugin for Proxy {
         Ok(self.client.send(proxied_req).await?.into())
     }
 
+    fn link(&mut self, _messenger: Rc<dyn Messenger>) {}
+
     fn context_mut(&mut self) -> &mut Context {
         unreachable!()
     }
@@ -1,11 +1,28 @@
+mod health;
+#[cfg(feature = "std")]
+mod metrics;
+mod middleware;
+mod pipeline;
 mod registry;
+mod schedule;
 mod vlugin_definition;
 
-pub use vlugin_definition::{VluginDef, VluginType};
+pub use middleware::{Flow, Middleware};
+pub use schedule::{civil_from_days, InvalidSchedule, Schedule};
+pub use vlugin_definition::{Preopen, Route, VluginDef, VluginType};
+
+/// Topics of the events published by the runtime, plugin events come with the plugin name
+/// as payload(e.g. `{ "name": "foo" }`)
+pub mod events {
+    pub const PLUGIN_LOADED: &str = "valor.plugin.loaded";
+    pub const PLUGIN_UNLOADED: &str = "valor.plugin.unloaded";
+    pub const SHUTDOWN: &str = "valor.shutdown";
+}
 
-use crate::{async_trait, Answer, Context, Message, Vlugin};
-use alloc::{borrow::ToOwned, boxed::Box, rc::Rc, string::String};
+use crate::{async_trait, http, Answer, Context, Message, Socket, Vlugin};
+use alloc::{borrow::ToOwned, boxed::Box, rc::Rc, string::String, vec::Vec};
 use core::{cell::RefCell, fmt, future::Future, pin::Pin};
+use pipeline::Pipeline;
 use registry::PluginRegistry;
 
 /// The runtime is a "Vlugin" itself that serves as the main entry point for
@@ -49,19 +66,39 @@ impl<L: Loader> Runtime<L> {
     }
 
     /// Uses the configured loader to load and register the provided plugin
-    pub async fn load_plugin(&self, mut plugin: VluginDef) -> Result<(), Error> {
-        let factory = self
-            .loader
-            .load(&plugin)
-            .await
-            .map_err(|_| Error::LoadVlugin(plugin.name.clone()))?;
-        let handler = factory(plugin.config.take())
-            .await
-            .map_err(|_| Error::InstantiateVlugin(plugin.name.clone()))?;
+    pub async fn load_plugin(&self, plugin: VluginDef) -> Result<(), Error> {
+        let name = plugin.name.clone();
+        let handler = self.instantiate(&plugin).await?;
         self.register_plugin(plugin, handler)?;
+        self.publish(events::PLUGIN_LOADED, serde_json::json!({ "name": name }))
+            .await;
+        Ok(())
+    }
+
+    /// Loads again an already registered plugin and swaps it with the running instance,
+    /// requests being handled by the old instance are allowed to finish
+    pub async fn reload_plugin(&self, name: &str) -> Result<(), Error> {
+        let plugin = self
+            .registry
+            .borrow()
+            .get(name)
+            .map(|(plugin, _)| plugin)
+            .ok_or_else(|| Error::VluginNotFound(name.into()))?;
+        let mut handler = self.instantiate(&plugin).await?;
+        handler.link(PluginRegistry::messenger(&self.registry));
+        let (_, replaced) = self
+            .registry
+            .borrow_mut()
+            .replace(name, plugin, handler)
+            .map_err(|_| Error::ReplaceVlugin(name.into()))?;
+        let _ = replaced.on_destroy().await;
         Ok(())
     }
 
+    async fn instantiate(&self, plugin: &VluginDef) -> Result<Box<dyn Vlugin>, Error> {
+        create(&self.registry, &*self.loader, plugin).await
+    }
+
     /// Expose the plugin registry as an endpoint on `_plugins` to add more plugins dynamically
     #[cfg(feature = "serde")]
     pub fn with_registry(self) -> Result<Self, Error> {
@@ -72,12 +109,40 @@ impl<L: Loader> Runtime<L> {
         Ok(self)
     }
 
-    /// Include the built-in health plugin that returns _Ok_ on `_health`
+    /// Include the built-in health plugin that reports the health of every plugin on
+    /// `_health` and `_health/ready` and that the runtime is up on `_health/live`
     pub fn with_health(self) -> Result<Self, Error> {
-        self.register_plugin("health", ())?;
+        let registry = Rc::downgrade(&self.registry);
+        self.register_plugin(health::NAME, health::Health(registry))?;
         Ok(self)
     }
 
+    /// Collect metrics of the requests plugins handle and the plugins that fail to load,
+    /// they are exposed on `_metrics` in the Prometheus text format
+    #[cfg(feature = "std")]
+    pub fn with_metrics(self) -> Result<Self, Error> {
+        let metrics = Rc::new(metrics::Metrics::default());
+        let handler = metrics::MetricsHandler(Rc::downgrade(&metrics));
+        self.registry.borrow_mut().metrics = Some(metrics);
+        self.register_plugin(("metrics", "_metrics"), handler)?;
+        Ok(self)
+    }
+
+    /// Adds a middleware that runs for every plugin
+    pub fn with_global_middleware(self, middleware: impl Middleware + 'static) -> Self {
+        self.registry
+            .borrow_mut()
+            .middlewares
+            .add_global(middleware);
+        self
+    }
+
+    /// Adds a middleware that plugins can use by listing its `name` in their definition
+    pub fn with_middleware(self, name: &str, middleware: impl Middleware + 'static) -> Self {
+        self.registry.borrow_mut().middlewares.add(name, middleware);
+        self
+    }
+
     /// Adds a plugin with its handler to the internal registry
     pub fn with_plugin<H>(self, plugin: impl Into<VluginDef>, handler: H) -> Result<Self, Error>
     where
@@ -91,7 +156,8 @@ impl<L: Loader> Runtime<L> {
     where
         H: Vlugin + 'static,
     {
-        let handler: Box<dyn Vlugin> = Box::new(handler);
+        let mut handler: Box<dyn Vlugin> = Box::new(handler);
+        handler.link(PluginRegistry::messenger(&self.registry));
         let plugin = plugin.into();
         let name = plugin.name.clone();
         self.registry
@@ -101,16 +167,97 @@ impl<L: Loader> Runtime<L> {
     }
 }
 
-#[async_trait(?Send)]
-impl<L> Vlugin for Runtime<L> {
-    /// Handles an incoming request by answering form a plugin that matches the URL pattern
-    ///
-    /// It requires the request to specify a `x-request-id` header that is set back on
-    /// the response as `x-correlation-id`(e.g. used by valor_web to match requests and responses)
-    async fn on_msg(&self, msg: Message) -> Result<Answer, crate::Error> {
-        use crate::http::{Error, StatusCode::*};
-        let mut request = match msg {
-            Message::Http(req) => req,
+impl<L> Runtime<L> {
+    /// Delivers an event to the plugins subscribed to its topic
+    pub async fn publish(&self, topic: &str, payload: serde_json::Value) {
+        PluginRegistry::messenger(&self.registry)
+            .publish(topic, payload)
+            .await
+    }
+
+    /// Sends a `Tick` to the plugins whose schedule is due at the given unix `timestamp`,
+    /// schedules have a granularity of a minute so it's meant to be called once a minute
+    pub async fn tick(&self, timestamp: u64) {
+        let scheduled = self.registry.borrow().scheduled(timestamp);
+        for handler in scheduled {
+            let _ = handler.on_msg(Message::Tick(timestamp)).await;
+        }
+    }
+
+    /// Lets plugins write to a WebSocket the server is about to open with a `WsConnect`
+    /// message, it's forgotten when the connection is refused or closed
+    pub fn add_socket(&self, id: &str, socket: impl Socket + 'static) -> Result<(), Error> {
+        self.registry
+            .borrow_mut()
+            .add_socket(id, Rc::new(socket))
+            .map_err(|_| Error::SocketInUse(id.into()))
+    }
+
+    /// Closes every open WebSocket, e.g. before the server shuts down
+    pub fn close_sockets(&self) {
+        let sockets = self.registry.borrow().sockets();
+        for socket in sockets {
+            socket.close();
+        }
+    }
+
+    /// Hands an upgrade request to the plugin matching its path, the plugin that accepts it
+    /// gets the messages of the WebSocket from now on
+    async fn connect(&self, id: String, request: http::Request) -> Result<Answer, crate::Error> {
+        let routed = self
+            .route(Message::WsConnect {
+                id: id.clone(),
+                request,
+            })
+            .await;
+        let mut registry = self.registry.borrow_mut();
+        match routed {
+            Ok((plugin, Answer::WsConnect)) => {
+                registry.bind_socket(&id, &plugin);
+                Ok(Answer::WsConnect)
+            }
+            routed => {
+                registry.remove_socket(&id);
+                routed.map(|(_, answer)| answer)
+            }
+        }
+    }
+
+    /// Delivers messages of an open WebSocket to the plugin that accepted it
+    async fn socket_msg(&self, msg: Message) -> Result<Answer, crate::Error> {
+        let handler = match &msg {
+            Message::WsFrame { id, .. } => self.registry.borrow().socket_handler(id),
+            Message::WsClose { id } => {
+                let handler = self.registry.borrow().socket_handler(id);
+                self.registry.borrow_mut().remove_socket(id);
+                handler
+            }
+            _ => return Err(crate::Error::NotSupported),
+        };
+        let not_found = || http::Error::from_str(http::StatusCode::NotFound, "Socket not found");
+        handler.ok_or_else(not_found)?.on_msg(msg).await
+    }
+
+    /// Lets plugins know the runtime is about to stop and unregisters them giving them
+    /// the chance to clean up with their `on_destroy` hook
+    pub async fn shutdown(&self) {
+        self.publish(events::SHUTDOWN, serde_json::Value::Null)
+            .await;
+        let plugins = self.registry.borrow_mut().unregister_all();
+        for (_, handler) in plugins {
+            let _ = handler.on_destroy().await;
+        }
+    }
+
+    /// Finds the plugin for the request of an HTTP or WebSocket upgrade message and hands
+    /// it the message through the middlewares, returns the plugin name with its answer
+    async fn route(&self, msg: Message) -> Result<(String, Answer), crate::Error> {
+        use crate::http::{
+            Error,
+            StatusCode::{BadRequest, NotFound},
+        };
+        let request = match &msg {
+            Message::Http(request) | Message::WsConnect { request, .. } => request,
             _ => return Err(crate::Error::NotSupported),
         };
 
@@ -120,31 +267,65 @@ impl<L> Vlugin for Runtime<L> {
             .as_str()
             .to_owned();
 
-        let (plugin, handler) = self
+        let ((plugin, handler), params, methods) = self
             .registry
             .borrow()
-            .match_vlugin(request.url().path())
+            .match_vlugin(request.host(), request.url().path())
             .ok_or_else(|| Error::from_str(NotFound, "No plugin matched"))?;
+        let middlewares = self.registry.borrow().middlewares.for_plugin(&plugin)?;
+        #[cfg(feature = "std")]
+        let timer = self
+            .registry
+            .borrow()
+            .metrics
+            .clone()
+            .map(|metrics| metrics.start(&plugin.name));
 
-        let without_prefix = request
-            .url()
-            .path()
-            .trim_start_matches('/')
-            .strip_prefix(plugin.prefix_or_name())
-            .expect("prefix")
-            .to_owned();
-        request.url_mut().set_path(&without_prefix);
+        let answer = middleware::run(&middlewares, &plugin, msg, |msg| {
+            dispatch(&plugin, handler, params, &methods, msg)
+        })
+        .await;
+        #[cfg(feature = "std")]
+        if let Some(timer) = timer {
+            timer.finish(&answer);
+        }
 
-        handler.on_msg(request.into()).await.map(|out| match out {
+        let answer = match answer? {
             Answer::Http(mut res) => {
                 res.append_header("x-correlation-id", req_id)
                     .expect("valid header");
-                res.append_header("x-valor-plugin", plugin.name)
+                res.append_header("x-valor-plugin", plugin.name.as_str())
                     .expect("valid header");
                 res.into()
             }
+            Answer::WsConnect => Answer::WsConnect,
             _ => Answer::Pong,
-        })
+        };
+        Ok((plugin.name, answer))
+    }
+}
+
+#[async_trait(?Send)]
+impl<L> Vlugin for Runtime<L> {
+    /// Handles an incoming request by answering form a plugin that matches the URL pattern
+    ///
+    /// It requires the request to specify a `x-request-id` header that is set back on
+    /// the response as `x-correlation-id`(e.g. used by valor_web to match requests and responses)
+    async fn on_msg(&self, msg: Message) -> Result<Answer, crate::Error> {
+        match msg {
+            Message::Http(_) => self.route(msg).await.map(|(_, answer)| answer),
+            Message::WsConnect { id, request } => self.connect(id, request).await,
+            Message::WsFrame { .. } | Message::WsClose { .. } => self.socket_msg(msg).await,
+            Message::Event { topic, payload } => {
+                self.publish(&topic, payload).await;
+                Ok(Answer::Pong)
+            }
+            Message::Tick(timestamp) => {
+                self.tick(timestamp).await;
+                Ok(Answer::Pong)
+            }
+            Message::Ping | Message::Health => Err(crate::Error::NotSupported),
+        }
     }
 
     fn context(&self) -> &Context {
@@ -156,6 +337,76 @@ impl<L> Vlugin for Runtime<L> {
     }
 }
 
+/// Creates the handler of a plugin, pipelines are built from the registered plugins
+/// and every other kind of plugin comes from the loader
+async fn create<L: Loader>(
+    registry: &Rc<RefCell<PluginRegistry>>,
+    loader: &L,
+    plugin: &VluginDef,
+) -> Result<Box<dyn Vlugin>, Error> {
+    let handler = match &plugin.r#type {
+        VluginType::Pipeline { steps } => Pipeline::new(registry, &plugin.name, steps.clone())
+            .map(|pipeline| Box::new(pipeline) as Box<dyn Vlugin>),
+        _ => match loader.load(plugin).await {
+            Ok(factory) => factory(plugin.config.clone())
+                .await
+                .map_err(|_| Error::InstantiateVlugin(plugin.name.clone())),
+            Err(err) => Err(err),
+        },
+    };
+    #[cfg(feature = "std")]
+    if let (Err(err), Some(metrics)) = (&handler, &registry.borrow().metrics) {
+        metrics.failed(&plugin.name, err);
+    }
+    handler
+}
+
+/// Hands the message to the plugin unless its route doesn't allow the method
+async fn dispatch(
+    plugin: &VluginDef,
+    handler: Rc<dyn Vlugin>,
+    params: crate::Params,
+    methods: &[http::Method],
+    msg: Message,
+) -> Result<Answer, crate::Error> {
+    use crate::http::StatusCode::{MethodNotAllowed, NoContent};
+    let (mut request, socket) = match msg {
+        Message::Http(req) => (req, None),
+        Message::WsConnect { id, request } => (request, Some(id)),
+        msg => return handler.on_msg(msg).await,
+    };
+
+    let method = request.method();
+    if methods.is_empty() || methods.contains(&method) {
+        request.ext_mut().insert(params);
+        // middlewares are free to rewrite the path
+        let without_prefix = request
+            .url()
+            .path()
+            .trim_start_matches('/')
+            .strip_prefix(plugin.prefix_or_name())
+            .map(ToOwned::to_owned);
+        if let Some(path) = without_prefix {
+            request.url_mut().set_path(&path);
+        }
+        return match socket {
+            Some(id) => handler.on_msg(Message::WsConnect { id, request }).await,
+            None => handler.on_msg(request.into()).await,
+        };
+    }
+
+    // the route declares which methods it accepts so we can answer for the plugin
+    let mut allow = methods.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+    allow.push("OPTIONS");
+    let status = match method {
+        http::Method::Options => NoContent,
+        _ => MethodNotAllowed,
+    };
+    let mut res = http::Response::new(status);
+    res.insert_header(http::headers::ALLOW, allow.join(", "));
+    Ok(res.into())
+}
+
 impl<L> Clone for Runtime<L> {
     fn clone(&self) -> Self {
         Runtime {
@@ -172,6 +423,10 @@ pub enum Error {
     LoadVlugin(String),
     VluginNotSupported(VluginType),
     RegisterVlugin(String),
+    ReplaceVlugin(String),
+    VluginNotFound(String),
+    IncompatibleVlugin(String, String),
+    SocketInUse(String),
 }
 
 impl fmt::Display for Error {
@@ -180,7 +435,13 @@ impl fmt::Display for Error {
             Error::InstantiateVlugin(name) => write!(f, "Failed instantiating {}", name),
             Error::LoadVlugin(name) => write!(f, "Failed loading {}", name),
             Error::RegisterVlugin(name) => write!(f, "{} already registered", name),
+            Error::ReplaceVlugin(name) => write!(f, "Failed replacing {}", name),
             Error::VluginNotSupported(ty) => write!(f, "Loader doesn't support {:?}", ty),
+            Error::VluginNotFound(name) => write!(f, "{} is not registered", name),
+            Error::IncompatibleVlugin(name, reason) => {
+                write!(f, "{} is incompatible: {}", name, reason)
+            }
+            Error::SocketInUse(id) => write!(f, "Socket {} is already open", id),
         }
     }
 }
@@ -211,3 +472,595 @@ impl Loader for () {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method::*, Request, Response, StatusCode};
+    use core::convert::TryFrom;
+
+    fn request(method: http::Method, path: &str) -> Request {
+        let mut req = Request::new(method, format!("http://valor{}", path).as_str());
+        req.insert_header("x-request-id", "123");
+        req
+    }
+
+    #[async_std::test]
+    async fn answer_for_methods_the_route_doesnt_allow() -> Result<(), crate::Error> {
+        let mut plugin: VluginDef = ("users", "users").into();
+        plugin.routes = vec![("/:id", &[Get, Put][..]).into()];
+        let runtime = Runtime::new(()).with_plugin(plugin, ())?;
+
+        let res: Response = runtime
+            .on_msg(request(Put, "/users/1").into())
+            .await?
+            .into();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let res: Response = runtime
+            .on_msg(request(Delete, "/users/1").into())
+            .await?
+            .into();
+        assert_eq!(res.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(res.header("allow").unwrap(), "GET, PUT, OPTIONS");
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "users");
+
+        let res: Response = runtime
+            .on_msg(request(Options, "/users/1").into())
+            .await?
+            .into();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(res.header("allow").unwrap(), "GET, PUT, OPTIONS");
+        Ok(())
+    }
+
+    fn values(header: Option<&http::headers::HeaderValues>) -> String {
+        let values = header.into_iter().flatten().map(|v| v.as_str());
+        values.collect::<Vec<_>>().join(", ")
+    }
+
+    /// Tags messages on the way in and answers on the way out
+    struct Tag(&'static str);
+
+    #[async_trait(?Send)]
+    impl Middleware for Tag {
+        async fn before(
+            &self,
+            _cx: &mut Context,
+            _plugin: &VluginDef,
+            msg: Message,
+        ) -> Result<Flow, crate::Error> {
+            let mut req = Request::try_from(msg).map_err(|_| crate::Error::NotSupported)?;
+            if req.header("x-stop").is_some() {
+                return Ok(Flow::Answer(Response::new(StatusCode::Unauthorized).into()));
+            }
+            req.append_header("x-before", self.0).expect("valid header");
+            Ok(Flow::Continue(req.into()))
+        }
+
+        async fn after(
+            &self,
+            _cx: &mut Context,
+            _plugin: &VluginDef,
+            answer: Result<Answer, crate::Error>,
+        ) -> Result<Answer, crate::Error> {
+            let mut res = Response::from(answer?);
+            res.append_header("x-after", self.0).expect("valid header");
+            Ok(res.into())
+        }
+    }
+
+    #[async_std::test]
+    async fn middlewares_run_around_the_plugin() -> Result<(), crate::Error> {
+        let echo = crate::h(|req: Request, _| async move {
+            Ok(Response::from(values(req.header("x-before"))))
+        });
+        let mut plugin: VluginDef = "foo".into();
+        plugin.middlewares = vec!["b".into()];
+        let runtime = Runtime::new(())
+            .with_global_middleware(Tag("a"))
+            .with_middleware("b", Tag("b"))
+            .with_middleware("c", Tag("c"))
+            .with_plugin(plugin, echo)?;
+
+        let mut res: Response = runtime.on_msg(request(Get, "/_foo").into()).await?.into();
+        assert_eq!(res.body_string().await?, "a, b");
+        assert_eq!(values(res.header("x-after")), "b, a");
+        assert_eq!(res.header("x-valor-plugin").unwrap(), "foo");
+
+        let mut req = request(Get, "/_foo");
+        req.insert_header("x-stop", "");
+        let res: Response = runtime.on_msg(req.into()).await?.into();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert_eq!(values(res.header("x-after")), "a");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn messages_between_plugins_go_through_middlewares() -> Result<(), crate::Error> {
+        let echo = crate::h(|req: Request, _| async move {
+            Ok(Response::from(values(req.header("x-before"))))
+        });
+        let mut plugin: VluginDef = "foo".into();
+        plugin.middlewares = vec!["b".into()];
+        let runtime = Runtime::new(())
+            .with_global_middleware(Tag("a"))
+            .with_middleware("b", Tag("b"))
+            .with_plugin(plugin, echo)?;
+        let mut cx = Context::default();
+        cx.with_messenger(PluginRegistry::messenger(&runtime.registry));
+
+        let mut res: Response = cx.send("foo", request(Get, "/")).await?.into();
+        assert_eq!(res.body_string().await?, "a, b");
+        assert_eq!(values(res.header("x-after")), "b, a");
+
+        let mut req = request(Get, "/");
+        req.insert_header("x-stop", "");
+        let res: Response = cx.send("foo", req).await?.into();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct Greeter(Context);
+
+    #[async_trait(?Send)]
+    impl Vlugin for Greeter {
+        async fn on_msg(&self, msg: Message) -> Result<Answer, crate::Error> {
+            let mut res = Response::from(self.0.send("names", msg).await?);
+            let name = res.body_string().await?;
+            Ok(Response::from(format!("Hello {}!", name)).into())
+        }
+
+        fn context(&self) -> &Context {
+            &self.0
+        }
+        fn context_mut(&mut self) -> &mut Context {
+            &mut self.0
+        }
+    }
+
+    #[async_std::test]
+    async fn plugins_talk_to_each_other() -> Result<(), crate::Error> {
+        let names = crate::h(|req: Request, _| async move {
+            Ok(Response::from(req.url().path().trim_matches('/')))
+        });
+        let runtime = Runtime::new(())
+            .with_plugin("greeter", Greeter::default())?
+            .with_plugin("names", names)?;
+
+        let mut res: Response = runtime
+            .on_msg(request(Get, "/_greeter/Alice").into())
+            .await?
+            .into();
+        assert_eq!(res.body_string().await?, "Hello Alice!");
+
+        let cx = Context::default();
+        assert!(cx.send("names", ()).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn plugins_dont_run_without_their_middlewares() -> Result<(), crate::Error> {
+        let mut plugin: VluginDef = "foo".into();
+        plugin.middlewares = vec!["auth".into()];
+        let runtime = Runtime::new(()).with_plugin(plugin, ())?;
+        assert!(runtime.on_msg(request(Get, "/_foo").into()).await.is_err());
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct Listener(Rc<RefCell<Vec<String>>>, Context);
+
+    #[async_trait(?Send)]
+    impl Vlugin for Listener {
+        async fn on_msg(&self, msg: Message) -> Result<Answer, crate::Error> {
+            let record = match msg {
+                Message::Event { topic, payload } => format!("{} {}", topic, payload),
+                Message::Tick(timestamp) => format!("tick {}", timestamp),
+                _ => return Err(crate::Error::NotSupported),
+            };
+            self.0.borrow_mut().push(record);
+            Ok(Answer::Pong)
+        }
+
+        async fn on_destroy(&self) -> Result<(), crate::Error> {
+            self.0.borrow_mut().push("destroyed".into());
+            Ok(())
+        }
+
+        fn context(&self) -> &Context {
+            &self.1
+        }
+        fn context_mut(&mut self) -> &mut Context {
+            &mut self.1
+        }
+    }
+
+    #[async_std::test]
+    async fn subscribers_get_events() -> Result<(), crate::Error> {
+        let listener = Listener::default();
+        let events = listener.0.clone();
+        let mut plugin: VluginDef = "listener".into();
+        plugin.subscriptions = vec!["greeting".into(), events::SHUTDOWN.into()];
+        let runtime = Runtime::new(())
+            .with_plugin(plugin, listener)?
+            .with_plugin("deaf", Listener::default())?;
+
+        let mut cx = Context::default();
+        cx.with_messenger(PluginRegistry::messenger(&runtime.registry));
+        cx.publish("greeting", serde_json::json!("hi")).await?;
+        cx.publish("other", serde_json::json!("ignored")).await?;
+        let event = Message::Event {
+            topic: "greeting".into(),
+            payload: serde_json::json!({ "from": "outside" }),
+        };
+        runtime.on_msg(event).await?;
+        runtime.shutdown().await;
+
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                r#"greeting "hi""#,
+                r#"greeting {"from":"outside"}"#,
+                "valor.shutdown null",
+                "destroyed"
+            ]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn scheduled_plugins_get_ticks() -> Result<(), crate::Error> {
+        let listener = Listener::default();
+        let ticks = listener.0.clone();
+        let mut plugin: VluginDef = "listener".into();
+        plugin.schedule = Some("*/5 * * * *".parse().expect("valid schedule"));
+        let runtime = Runtime::new(())
+            .with_plugin(plugin, listener)?
+            .with_plugin("idle", Listener::default())?;
+
+        for minute in 0..11 {
+            runtime.tick(minute * 60).await;
+        }
+        runtime.on_msg(Message::Tick(3600)).await?;
+        assert_eq!(
+            *ticks.borrow(),
+            vec!["tick 0", "tick 300", "tick 600", "tick 3600"]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn plugins_are_destroyed_on_shutdown() -> Result<(), crate::Error> {
+        let listener = Listener::default();
+        let records = listener.0.clone();
+        let runtime = Runtime::new(()).with_plugin("listener", listener)?;
+
+        runtime.shutdown().await;
+        assert_eq!(*records.borrow(), vec!["destroyed"]);
+        let res = runtime.on_msg(request(Get, "/_listener").into()).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    /// Greets clients of the `room` path and echoes their frames
+    #[derive(Default)]
+    struct Chat(Context);
+
+    #[async_trait(?Send)]
+    impl Vlugin for Chat {
+        async fn on_msg(&self, msg: Message) -> Result<Answer, crate::Error> {
+            match msg {
+                Message::WsConnect { id, request } if request.url().path() == "/room" => {
+                    self.0.send_frame(&id, "welcome")?;
+                    Ok(Answer::WsConnect)
+                }
+                Message::WsFrame { frame, .. } => Ok(frame.into()),
+                _ => Ok(Answer::Pong),
+            }
+        }
+
+        fn context(&self) -> &Context {
+            &self.0
+        }
+        fn context_mut(&mut self) -> &mut Context {
+            &mut self.0
+        }
+    }
+
+    struct Sent(Rc<RefCell<Vec<crate::Frame>>>);
+
+    impl Socket for Sent {
+        fn send(&self, frame: crate::Frame) -> Result<(), crate::Error> {
+            self.0.borrow_mut().push(frame);
+            Ok(())
+        }
+        fn close(&self) {}
+    }
+
+    #[async_std::test]
+    async fn plugins_accept_sockets() -> Result<(), crate::Error> {
+        use crate::Frame;
+        let runtime = Runtime::new(()).with_plugin("chat", Chat::default())?;
+        let frame = |id: &str, text: &str| Message::WsFrame {
+            id: id.into(),
+            frame: text.into(),
+        };
+
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        runtime.add_socket("1", Sent(sent.clone()))?;
+        assert!(runtime.add_socket("1", Sent(Rc::default())).is_err());
+        let connect = Message::WsConnect {
+            id: "1".into(),
+            request: request(Get, "/_chat/room"),
+        };
+        assert!(matches!(runtime.on_msg(connect).await?, Answer::WsConnect));
+        assert_eq!(*sent.borrow(), vec![Frame::from("welcome")]);
+        let echo = runtime.on_msg(frame("1", "hello")).await?;
+        assert!(matches!(echo, Answer::WsFrame(Frame::Text(t)) if t == "hello"));
+
+        runtime.on_msg(Message::WsClose { id: "1".into() }).await?;
+        assert!(runtime.on_msg(frame("1", "hello")).await.is_err());
+
+        // refused sockets are forgotten
+        runtime.add_socket("2", Sent(Rc::default()))?;
+        let connect = Message::WsConnect {
+            id: "2".into(),
+            request: request(Get, "/_chat/hall"),
+        };
+        assert!(matches!(runtime.on_msg(connect).await?, Answer::Pong));
+        assert!(runtime.registry.borrow().socket("2").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn open_sockets_are_closed() -> Result<(), crate::Error> {
+        struct Closed(Rc<core::cell::Cell<bool>>);
+
+        impl Socket for Closed {
+            fn send(&self, _frame: crate::Frame) -> Result<(), crate::Error> {
+                Ok(())
+            }
+            fn close(&self) {
+                self.0.set(true);
+            }
+        }
+
+        let runtime = Runtime::new(());
+        let closed = Rc::new(core::cell::Cell::new(false));
+        runtime.add_socket("1", Closed(closed.clone()))?;
+        runtime.close_sockets();
+        assert!(closed.get());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn closures_only_handle_requests() -> Result<(), crate::Error> {
+        let echo = || {
+            crate::h(|req: Request, _| async move {
+                let res: Response = req.url().path().into();
+                Ok(res)
+            })
+        };
+        let event = Message::Event {
+            topic: "news".into(),
+            payload: serde_json::Value::Null,
+        };
+        assert!(matches!(echo().on_msg(Message::Ping).await?, Answer::Pong));
+        assert!(matches!(
+            echo().on_msg(event).await,
+            Err(crate::Error::NotSupported)
+        ));
+        assert!(matches!(
+            echo().on_msg(Message::Tick(0)).await,
+            Err(crate::Error::NotSupported)
+        ));
+
+        let mut plugin: VluginDef = "echo".into();
+        plugin.subscriptions = vec!["news".into()];
+        plugin.schedule = Some("* * * * *".parse().expect("valid schedule"));
+        let runtime = Runtime::new(()).with_plugin(plugin, echo())?;
+        runtime.add_socket("1", Sent(Rc::default()))?;
+        let connect = Message::WsConnect {
+            id: "1".into(),
+            request: request(Get, "/_echo"),
+        };
+        assert!(runtime.on_msg(connect).await.is_err());
+        runtime.publish("news", serde_json::Value::Null).await;
+        runtime.tick(0).await;
+
+        let mut res: Response = runtime.on_msg(request(Get, "/_echo").into()).await?.into();
+        assert_eq!(res.body_string().await?, "/");
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[async_std::test]
+    async fn metrics_of_requests_and_failed_plugins() -> Result<(), crate::Error> {
+        let mut plugin: VluginDef = ("users", "users").into();
+        plugin.routes = vec![("/:id", &[Get][..]).into()];
+        let runtime = Runtime::new(()).with_metrics()?.with_plugin(plugin, ())?;
+
+        runtime.on_msg(request(Get, "/users/1").into()).await?;
+        runtime.on_msg(request(Get, "/users/2").into()).await?;
+        runtime.on_msg(request(Delete, "/users/1").into()).await?;
+        let mut pipeline: VluginDef = "empty".into();
+        pipeline.r#type = VluginType::Pipeline { steps: vec![] };
+        assert!(runtime.load_plugin(pipeline).await.is_err());
+
+        let mut res: Response = runtime
+            .on_msg(request(Get, "/_metrics").into())
+            .await?
+            .into();
+        assert_eq!(
+            res.header("content-type").unwrap(),
+            "text/plain; version=0.0.4"
+        );
+        let metrics = res.body_string().await?;
+        for line in &[
+            r#"valor_requests_total{plugin="users",status="2xx"} 2"#,
+            r#"valor_requests_total{plugin="users",status="4xx"} 1"#,
+            r#"valor_request_duration_seconds_bucket{plugin="users",le="+Inf"} 3"#,
+            r#"valor_request_duration_seconds_count{plugin="users"} 3"#,
+            // the request for the metrics is still being handled
+            r#"valor_requests_in_flight{plugin="metrics"} 1"#,
+            r#"valor_requests_in_flight{plugin="users"} 0"#,
+            r#"valor_plugin_instantiate_failures_total{plugin="empty"} 1"#,
+        ] {
+            assert!(
+                metrics.lines().any(|l| l == *line),
+                "{} in\n{}",
+                line,
+                metrics
+            );
+        }
+        Ok(())
+    }
+
+    /// Plugin that fails its health check
+    struct Unhealthy;
+
+    #[async_trait(?Send)]
+    impl Vlugin for Unhealthy {
+        async fn on_msg(&self, msg: Message) -> Result<Answer, crate::Error> {
+            match msg {
+                Message::Health => Err(http::Error::from_str(
+                    StatusCode::ServiceUnavailable,
+                    "Database unreachable",
+                )
+                .into()),
+                _ => Ok(Answer::Pong),
+            }
+        }
+
+        fn link(&mut self, _messenger: Rc<dyn crate::Messenger>) {}
+
+        fn context(&self) -> &Context {
+            unreachable!()
+        }
+        fn context_mut(&mut self) -> &mut Context {
+            unreachable!()
+        }
+    }
+
+    #[async_std::test]
+    async fn health_of_plugins() -> Result<(), crate::Error> {
+        let mut cache: VluginDef = "cache".into();
+        cache.optional = true;
+        let runtime = Runtime::new(())
+            .with_health()?
+            .with_plugin("users", ())?
+            .with_plugin(cache, Unhealthy)?;
+        let runtime = &runtime;
+        let health = |path| async move {
+            let mut res: Response = runtime.on_msg(request(Get, path).into()).await?.into();
+            let body: serde_json::Value = serde_json::from_str(&res.body_string().await?)
+                .map_err(|e| http::Error::new(StatusCode::InternalServerError, e))?;
+            Ok::<_, crate::Error>((res.status(), body))
+        };
+
+        let (status, body) = health("/_health").await?;
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(body["status"], "up");
+        assert_eq!(body["plugins"]["users"]["status"], "up");
+        assert_eq!(body["plugins"]["cache"]["status"], "down");
+        assert_eq!(body["plugins"]["cache"]["error"], "Database unreachable");
+        assert!(body["plugins"].get("health").is_none());
+
+        runtime.register_plugin("db", Unhealthy)?;
+        for path in &["/_health", "/_health/ready"] {
+            let (status, body) = health(path).await?;
+            assert_eq!(status, StatusCode::ServiceUnavailable);
+            assert_eq!(body["status"], "down");
+            assert_eq!(body["plugins"]["db"]["optional"], false);
+        }
+        let (status, body) = health("/_health/live").await?;
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(body, serde_json::json!({ "status": "up" }));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn plugins_that_only_answer_requests_are_healthy() -> Result<(), crate::Error> {
+        let echo = crate::h(|req: Request, _| async move {
+            let res: Response = req.url().path().into();
+            Ok(res)
+        });
+        let runtime = Runtime::new(()).with_health()?.with_plugin("echo", echo)?;
+        #[cfg(feature = "proxy")]
+        let runtime = {
+            let proxy = crate::proxy::Proxy::try_from(String::from("http://localhost"))?;
+            runtime.with_plugin("proxy", proxy)?
+        };
+
+        let mut res: Response = runtime
+            .on_msg(request(Get, "/_health/ready").into())
+            .await?
+            .into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body = res.body_string().await?;
+        assert!(body.contains(r#""echo":{"status":"up"}"#), "{}", body);
+        #[cfg(feature = "proxy")]
+        assert!(body.contains(r#""proxy":{"status":"up"}"#), "{}", body);
+        Ok(())
+    }
+
+    #[cfg(all(feature = "std", feature = "serde"))]
+    #[async_std::test]
+    async fn metrics_of_plugins_failing_through_the_registry() -> Result<(), crate::Error> {
+        let runtime = Runtime::new(()).with_metrics()?.with_registry()?;
+        let mut req = request(Post, "/_plugins");
+        req.set_body(r#"{ "name": "looped", "type": "pipeline", "steps": ["looped"] }"#);
+        assert!(runtime.on_msg(req.into()).await.is_err());
+
+        let mut res: Response = runtime
+            .on_msg(request(Get, "/_metrics").into())
+            .await?
+            .into();
+        let metrics = res.body_string().await?;
+        let failed = r#"valor_plugin_instantiate_failures_total{plugin="looped"} 1"#;
+        assert!(metrics.lines().any(|l| l == failed), "{}", metrics);
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[async_std::test]
+    async fn registry_doesnt_list_plugin_configs() -> Result<(), crate::Error> {
+        let mut plugin = VluginDef::from("secret");
+        plugin.config = Some(serde_json::json!({ "token": "s3cr3t" }));
+        let runtime = Runtime::new(()).with_registry()?.with_plugin(
+            plugin,
+            crate::h(|_: Request, _| async { Ok(Response::new(200)) }),
+        )?;
+
+        let mut res: Response = runtime
+            .on_msg(request(Get, "/_plugins").into())
+            .await?
+            .into();
+        let body = res.body_string().await?;
+        assert!(body.contains(r#""name":"secret""#), "{}", body);
+        assert!(!body.contains("s3cr3t"), "{}", body);
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[async_std::test]
+    async fn built_in_plugins_stay_registered() -> Result<(), crate::Error> {
+        let runtime = Runtime::new(()).with_health()?.with_registry()?;
+        for name in ["registry", "health"] {
+            let path = format!("/_plugins/{}", name);
+            let forbidden = match runtime.on_msg(request(Delete, &path).into()).await {
+                Err(crate::Error::Http(err)) => err.status() == StatusCode::Forbidden,
+                _ => false,
+            };
+            assert!(forbidden, "{} was removed", name);
+        }
+        let res: Response = runtime
+            .on_msg(request(Get, "/_health").into())
+            .await?
+            .into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        Ok(())
+    }
+}
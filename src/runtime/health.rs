@@ -0,0 +1,81 @@
+use super::registry::PluginRegistry;
+use crate::{async_trait, http, Answer, Error, Message, Messenger, Vlugin};
+use alloc::{
+    boxed::Box,
+    rc::{Rc, Weak},
+    string::ToString,
+    vec::Vec,
+};
+use core::cell::RefCell;
+use serde_json::{json, Map, Value};
+
+pub(crate) const NAME: &str = "health";
+
+/// Built-in plugin mounted on `_health`, `_health/live` only tells the runtime is up
+/// while `_health` and `_health/ready` send a `Health` message to every plugin and
+/// answer with _Service Unavailable_ when a plugin that isn't optional fails it.
+/// Plugins that don't support the message are considered healthy
+pub(crate) struct Health(pub Weak<RefCell<PluginRegistry>>);
+
+impl Health {
+    /// Plugins are checked one after the other, returns the report and whether
+    /// the runtime is ready
+    async fn check(&self) -> (bool, Value) {
+        let plugins = match self.0.upgrade() {
+            Some(registry) => registry.borrow().all(),
+            None => Vec::new(),
+        };
+        let mut ready = true;
+        let mut report = Map::new();
+        for (plugin, handler) in plugins.into_iter().filter(|(p, _)| p.name != NAME) {
+            let status = match handler.on_msg(Message::Health).await {
+                Ok(_) | Err(Error::NotSupported) => json!({ "status": "up" }),
+                Err(err) => {
+                    ready &= plugin.optional;
+                    json!({ "status": "down", "optional": plugin.optional, "error": err.to_string() })
+                }
+            };
+            report.insert(plugin.name, status);
+        }
+        (ready, Value::Object(report))
+    }
+}
+
+#[async_trait(?Send)]
+impl Vlugin for Health {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        use http::{mime, Response, StatusCode};
+        let req = match msg {
+            Message::Http(req) => req,
+            _ => return Ok(Answer::Pong),
+        };
+
+        let (status, report) = match req.url().path().trim_matches('/') {
+            "live" => (StatusCode::Ok, json!({ "status": "up" })),
+            "" | "ready" => match self.check().await {
+                (true, plugins) => (
+                    StatusCode::Ok,
+                    json!({ "status": "up", "plugins": plugins }),
+                ),
+                (false, plugins) => (
+                    StatusCode::ServiceUnavailable,
+                    json!({ "status": "down", "plugins": plugins }),
+                ),
+            },
+            _ => return Ok(Response::new(StatusCode::NotFound).into()),
+        };
+        let mut res = Response::new(status);
+        res.set_body(report.to_string());
+        res.set_content_type(mime::JSON);
+        Ok(res.into())
+    }
+
+    fn link(&mut self, _messenger: Rc<dyn Messenger>) {}
+
+    fn context(&self) -> &crate::Context {
+        unimplemented!()
+    }
+    fn context_mut(&mut self) -> &mut crate::Context {
+        unimplemented!()
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{async_trait, http, Answer, Error, Message, Messenger, Vlugin};
+use alloc::{
+    collections::BTreeMap,
+    rc::{Rc, Weak},
+    string::String,
+};
+use core::{cell::RefCell, fmt::Write};
+use std::time::Instant;
+
+/// Upper bounds in seconds of the buckets of the latency histograms
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters the runtime updates as it dispatches messages and loads plugins
+#[derive(Default)]
+pub(crate) struct Metrics(RefCell<State>);
+
+#[derive(Default)]
+struct State {
+    requests: BTreeMap<(String, &'static str), u64>,
+    latency: BTreeMap<String, Histogram>,
+    in_flight: BTreeMap<String, u64>,
+    load_failures: BTreeMap<String, u64>,
+    instantiate_failures: BTreeMap<String, u64>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    /// Counts a request the plugin is handling until the returned timer is finished or dropped
+    pub fn start(self: &Rc<Self>, plugin: &str) -> Timer {
+        *self
+            .0
+            .borrow_mut()
+            .in_flight
+            .entry(plugin.into())
+            .or_default() += 1;
+        Timer {
+            metrics: self.clone(),
+            plugin: plugin.into(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Counts a plugin that couldn't be loaded or instantiated
+    pub fn failed(&self, plugin: &str, err: &super::Error) {
+        let mut state = self.0.borrow_mut();
+        let failures = match err {
+            super::Error::InstantiateVlugin(_) => &mut state.instantiate_failures,
+            _ => &mut state.load_failures,
+        };
+        *failures.entry(plugin.into()).or_default() += 1;
+    }
+
+    /// Metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let state = self.0.borrow();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "valor_requests_total",
+            "counter",
+            "Requests answered by each plugin",
+        );
+        for ((plugin, class), count) in &state.requests {
+            let _ = writeln!(
+                out,
+                "valor_requests_total{{plugin=\"{}\",status=\"{}\"}} {}",
+                escape(plugin),
+                class,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "valor_request_duration_seconds",
+            "histogram",
+            "Time plugins take to answer",
+        );
+        for (plugin, histogram) in &state.latency {
+            let plugin = escape(plugin);
+            for (le, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "valor_request_duration_seconds_bucket{{plugin=\"{}\",le=\"{}\"}} {}",
+                    plugin, le, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "valor_request_duration_seconds_bucket{{plugin=\"{}\",le=\"+Inf\"}} {}",
+                plugin, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "valor_request_duration_seconds_sum{{plugin=\"{}\"}} {}",
+                plugin, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "valor_request_duration_seconds_count{{plugin=\"{}\"}} {}",
+                plugin, histogram.count
+            );
+        }
+
+        header(
+            &mut out,
+            "valor_requests_in_flight",
+            "gauge",
+            "Requests plugins are handling",
+        );
+        for (plugin, count) in &state.in_flight {
+            let _ = writeln!(
+                out,
+                "valor_requests_in_flight{{plugin=\"{}\"}} {}",
+                escape(plugin),
+                count
+            );
+        }
+
+        let failures = [
+            (
+                "valor_plugin_load_failures_total",
+                "Plugins the loader failed to load",
+                &state.load_failures,
+            ),
+            (
+                "valor_plugin_instantiate_failures_total",
+                "Plugins that failed to instantiate",
+                &state.instantiate_failures,
+            ),
+        ];
+        for &(name, help, counters) in &failures {
+            header(&mut out, name, "counter", help);
+            for (plugin, count) in counters {
+                let _ = writeln!(out, "{}{{plugin=\"{}\"}} {}", name, escape(plugin), count);
+            }
+        }
+        out
+    }
+}
+
+/// Measures a request from the moment its plugin is matched
+pub(crate) struct Timer {
+    metrics: Rc<Metrics>,
+    plugin: String,
+    started: Instant,
+}
+
+impl Timer {
+    /// Records the latency and status class of the answer
+    pub fn finish(self, answer: &Result<Answer, Error>) {
+        let status = match answer {
+            Ok(Answer::Http(res)) => res.status().into(),
+            Ok(Answer::WsConnect) => 101,
+            Ok(_) => 200,
+            Err(Error::Http(err)) => err.status().into(),
+            Err(_) => 500,
+        };
+        let class = match status {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            _ => "5xx",
+        };
+        let elapsed = self.started.elapsed().as_secs_f64();
+
+        let mut state = self.metrics.0.borrow_mut();
+        *state
+            .requests
+            .entry((self.plugin.clone(), class))
+            .or_default() += 1;
+        let histogram = state.latency.entry(self.plugin.clone()).or_default();
+        for (le, count) in BUCKETS.iter().zip(histogram.buckets.iter_mut()) {
+            if elapsed <= *le {
+                *count += 1;
+            }
+        }
+        histogram.sum += elapsed;
+        histogram.count += 1;
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(count) = self.metrics.0.borrow_mut().in_flight.get_mut(&self.plugin) {
+            *count -= 1;
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, ty);
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Built-in plugin that exposes the metrics to be scraped
+pub(crate) struct MetricsHandler(pub Weak<Metrics>);
+
+#[async_trait(?Send)]
+impl Vlugin for MetricsHandler {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        match msg {
+            Message::Http(_) => {
+                let mut res = http::Response::new(http::StatusCode::Ok);
+                let metrics = self.0.upgrade().map(|m| m.render());
+                res.set_body(metrics.unwrap_or_default());
+                res.insert_header(http::headers::CONTENT_TYPE, "text/plain; version=0.0.4");
+                Ok(res.into())
+            }
+            _ => Ok(Answer::Pong),
+        }
+    }
+
+    fn link(&mut self, _messenger: Rc<dyn Messenger>) {}
+
+    fn context(&self) -> &crate::Context {
+        unimplemented!()
+    }
+    fn context_mut(&mut self) -> &mut crate::Context {
+        unimplemented!()
+    }
+}
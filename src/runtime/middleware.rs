@@ -0,0 +1,126 @@
+use super::VluginDef;
+use crate::{async_trait, http, Answer, Context, Error, Message};
+use alloc::{boxed::Box, format, rc::Rc, string::String, vec::Vec};
+use core::future::Future;
+use hashbrown::HashMap;
+
+/// What happens with a message after a middleware saw it
+pub enum Flow {
+    /// Pass the message on to the next middleware or the plugin
+    Continue(Message),
+    /// Answer right away without reaching the plugin
+    Answer(Answer),
+}
+
+/// Middlewares run around the plugin that handles a message to take care of
+/// concerns shared by many plugins like authentication, logging or CORS.
+///
+/// `before` hooks run in the order middlewares were added and `after` hooks in
+/// reverse, the `after` hook of a middleware runs only if its `before` hook did.
+/// The `cx` is created for every message and shared by its middlewares so data
+/// can be passed between hooks, each middleware should store its own types.
+///
+/// ```
+/// # use valor_core::*;
+/// # use runtime::{Flow, Middleware, Runtime, VluginDef};
+/// struct Auth;
+///
+/// #[async_trait(?Send)]
+/// impl Middleware for Auth {
+///     async fn before(&self, _cx: &mut Context, _plugin: &VluginDef, msg: Message) -> Result<Flow, Error> {
+///         match &msg {
+///             Message::Http(req) if req.header("authorization").is_none() => {
+///                 Ok(Flow::Answer(http::Response::new(401).into()))
+///             }
+///             _ => Ok(Flow::Continue(msg)),
+///         }
+///     }
+/// }
+///
+/// let runtime = Runtime::new(()).with_middleware("auth", Auth);
+/// ```
+#[async_trait(?Send)]
+pub trait Middleware {
+    /// Runs before the plugin gets the message, it can modify it or answer it
+    async fn before(
+        &self,
+        _cx: &mut Context,
+        _plugin: &VluginDef,
+        msg: Message,
+    ) -> Result<Flow, Error> {
+        Ok(Flow::Continue(msg))
+    }
+
+    /// Runs with the outcome of handling the message, it can modify it as well
+    async fn after(
+        &self,
+        _cx: &mut Context,
+        _plugin: &VluginDef,
+        answer: Result<Answer, Error>,
+    ) -> Result<Answer, Error> {
+        answer
+    }
+}
+
+/// Middlewares that run for every plugin and the ones plugins can choose by name
+#[derive(Default)]
+pub(crate) struct Middlewares {
+    global: Vec<Rc<dyn Middleware>>,
+    named: HashMap<String, Rc<dyn Middleware>>,
+}
+
+impl Middlewares {
+    pub fn add_global(&mut self, middleware: impl Middleware + 'static) {
+        self.global.push(Rc::new(middleware));
+    }
+
+    pub fn add(&mut self, name: &str, middleware: impl Middleware + 'static) {
+        self.named.insert(name.into(), Rc::new(middleware));
+    }
+
+    /// Global middlewares followed by the ones the plugin asks for, a missing
+    /// middleware is an error so plugins don't run without e.g. authentication
+    pub fn for_plugin(&self, plugin: &VluginDef) -> Result<Vec<Rc<dyn Middleware>>, Error> {
+        let named = plugin.middlewares.iter().map(|name| {
+            self.named.get(name).cloned().ok_or_else(|| {
+                let msg = format!("Middleware {} not found", name);
+                http::Error::from_str(http::StatusCode::InternalServerError, msg).into()
+            })
+        });
+        self.global.iter().cloned().map(Ok).chain(named).collect()
+    }
+}
+
+/// Hands the message to `handle` through the middlewares, their `after` hooks
+/// get the answer in reverse order
+pub(crate) async fn run<F, Fut>(
+    middlewares: &[Rc<dyn Middleware>],
+    plugin: &VluginDef,
+    msg: Message,
+    handle: F,
+) -> Result<Answer, Error>
+where
+    F: FnOnce(Message) -> Fut,
+    Fut: Future<Output = Result<Answer, Error>>,
+{
+    let mut cx = Context::default();
+    let mut flow = Ok(Flow::Continue(msg));
+    let mut ran = 0;
+    for middleware in middlewares {
+        let msg = match flow {
+            Ok(Flow::Continue(msg)) => msg,
+            _ => break,
+        };
+        flow = middleware.before(&mut cx, plugin, msg).await;
+        ran += 1;
+    }
+    let mut answer = match flow {
+        Ok(Flow::Continue(msg)) => handle(msg).await,
+        Ok(Flow::Answer(answer)) => Ok(answer),
+        Err(err) => Err(err),
+    };
+    for middleware in middlewares[..ran].iter().rev() {
+        answer = middleware.after(&mut cx, plugin, answer).await;
+    }
+    answer
+}
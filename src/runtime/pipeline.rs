@@ -0,0 +1,214 @@
+use super::registry::PluginRegistry;
+use crate::{async_trait, http, Answer, Context, Error, Message, Params, Vlugin};
+use alloc::{
+    boxed::Box,
+    format,
+    rc::{Rc, Weak},
+    string::String,
+    vec::Vec,
+};
+use core::cell::RefCell;
+
+/// Vlugin that passes messages through a chain of registered plugins.
+///
+/// A step that fails or answers with a non successful response stops the
+/// pipeline and its answer is the answer of the pipeline. A step that answers
+/// with an empty successful response lets the request through unchanged(e.g. an
+/// authentication step) and one that answers with a body makes it the body of
+/// the request the next step gets(e.g. a transformation step). The answer of
+/// the last step is the answer of the pipeline.
+///
+/// Steps are looked up every time so they can be replaced while the pipeline runs,
+/// note that they are called directly skipping their own routes and middlewares.
+/// Every step gets the params captured by the route of the pipeline but only the last
+/// one gets the other extensions of the request, e.g. data added by middlewares.
+pub(crate) struct Pipeline {
+    // the registry owns the pipeline
+    registry: Weak<RefCell<PluginRegistry>>,
+    steps: Vec<String>,
+    cx: Context,
+}
+
+impl Pipeline {
+    /// Pipelines need at least one step and can't be a step of themselves,
+    /// neither directly nor through the registered pipelines they run
+    pub fn new(
+        registry: &Rc<RefCell<PluginRegistry>>,
+        name: &str,
+        steps: Vec<String>,
+    ) -> Result<Self, super::Error> {
+        if steps.is_empty() || registry.borrow().leads_to(&steps, name) {
+            return Err(super::Error::InstantiateVlugin(name.into()));
+        }
+        Ok(Pipeline {
+            registry: Rc::downgrade(registry),
+            steps,
+            cx: Context::default(),
+        })
+    }
+
+    fn step(&self, name: &str) -> Result<Rc<dyn Vlugin>, Error> {
+        let step = self.registry.upgrade().and_then(|r| r.borrow().get(name));
+        let (_, handler) = step.ok_or_else(|| {
+            let msg = format!("Pipeline step {} not found", name);
+            http::Error::from_str(http::StatusCode::InternalServerError, msg)
+        })?;
+        Ok(handler)
+    }
+}
+
+#[async_trait(?Send)]
+impl Vlugin for Pipeline {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
+        let mut req = match msg {
+            Message::Http(req) => req,
+            Message::Ping | Message::Health => return Ok(Answer::Pong),
+            _ => return Err(Error::NotSupported),
+        };
+
+        let (last, steps) = self.steps.split_last().expect("pipeline has steps");
+        let mut body = req.body_bytes().await?;
+        for step in steps {
+            // extensions can't be cloned, the ones steps know about are copied
+            let mut step_req = req.clone();
+            if let Some(params) = req.ext().get::<Params>() {
+                step_req.ext_mut().insert(params.clone());
+            }
+            step_req.set_body(body.clone());
+            let mut res = match self.step(step)?.on_msg(step_req.into()).await? {
+                Answer::Http(res) => res,
+                _ => continue,
+            };
+            if !res.status().is_success() {
+                return Ok(res.into());
+            }
+            let res_body = res.body_bytes().await?;
+            if res_body.is_empty() {
+                continue;
+            }
+            body = res_body;
+            if let Some(mime) = res.content_type() {
+                req.set_content_type(mime);
+            }
+        }
+        req.set_body(body);
+        self.step(last)?.on_msg(req.into()).await
+    }
+
+    fn context(&self) -> &Context {
+        &self.cx
+    }
+    fn context_mut(&mut self) -> &mut Context {
+        &mut self.cx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{VluginDef, VluginType};
+    use crate::{
+        h,
+        http::{Method, Request, Response, StatusCode},
+    };
+
+    fn pipeline(steps: &[&str]) -> (Rc<RefCell<PluginRegistry>>, Pipeline) {
+        let registry = Rc::new(RefCell::new(PluginRegistry::new()));
+        {
+            let mut reg = registry.borrow_mut();
+            let auth = h(|req: Request, _| async move {
+                Ok(match req.header("authorization") {
+                    Some(_) => Response::new(StatusCode::NoContent),
+                    None => Response::new(StatusCode::Unauthorized),
+                })
+            });
+            let upper = h(|mut req: Request, _| async move {
+                let body = req.body_string().await?;
+                Ok(Response::from(body.to_uppercase()))
+            });
+            let echo = h(|mut req: Request, _| async move {
+                Ok(Response::from(req.body_string().await?))
+            });
+            let greet = h(|req: Request, _| async move {
+                let name = req.ext().get::<Params>().and_then(|p| p.get("name"));
+                Ok(Response::from(format!(
+                    "hello {}",
+                    name.unwrap_or("nobody")
+                )))
+            });
+            reg.register("auth".into(), auth).unwrap();
+            reg.register("greet".into(), greet).unwrap();
+            reg.register("upper".into(), upper).unwrap();
+            reg.register("echo".into(), echo).unwrap();
+        }
+        let steps = steps.iter().map(|&s| s.into()).collect();
+        let pipeline = Pipeline::new(&registry, "pipeline", steps).unwrap();
+        (registry, pipeline)
+    }
+
+    fn request(authorized: bool) -> Request {
+        let mut req = Request::new(Method::Post, "http://valor/");
+        if authorized {
+            req.insert_header("authorization", "yes");
+        }
+        req.set_body("hello");
+        req
+    }
+
+    #[async_std::test]
+    async fn steps_transform_the_request() -> Result<(), Error> {
+        let (_registry, pipeline) = pipeline(&["auth", "upper", "echo"]);
+        let mut res: Response = pipeline.on_msg(request(true).into()).await?.into();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await?, "HELLO");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn steps_get_the_params_of_the_route() -> Result<(), Error> {
+        let (_registry, pipeline) = pipeline(&["greet", "upper", "echo"]);
+        let mut req = request(true);
+        let params = vec![("name", "alice")].into_iter().collect::<Params>();
+        req.ext_mut().insert(params);
+        let mut res: Response = pipeline.on_msg(req.into()).await?.into();
+        assert_eq!(res.body_string().await?, "HELLO ALICE");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn steps_can_stop_the_pipeline() -> Result<(), Error> {
+        let (_registry, pipeline) = pipeline(&["auth", "upper", "echo"]);
+        let res: Response = pipeline.on_msg(request(false).into()).await?.into();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        Ok(())
+    }
+
+    #[test]
+    fn pipelines_need_steps() {
+        let registry = Rc::new(RefCell::new(PluginRegistry::new()));
+        assert!(Pipeline::new(&registry, "foo", Vec::new()).is_err());
+        assert!(Pipeline::new(&registry, "foo", vec!["bar".into(), "foo".into()]).is_err());
+    }
+
+    #[test]
+    fn pipelines_cant_run_each_other_in_a_loop() {
+        let registry = Rc::new(RefCell::new(PluginRegistry::new()));
+        let a = Pipeline::new(&registry, "a", vec!["b".into()]).unwrap();
+        let mut def: VluginDef = "a".into();
+        def.r#type = VluginType::Pipeline {
+            steps: vec!["b".into()],
+        };
+        registry.borrow_mut().register(def, a).unwrap();
+
+        assert!(Pipeline::new(&registry, "b", vec!["echo".into(), "a".into()]).is_err());
+        assert!(Pipeline::new(&registry, "b", vec!["echo".into()]).is_ok());
+        // a registered pipeline can't be rebuilt to run itself either
+        assert!(Pipeline::new(&registry, "a", vec!["a".into()]).is_err());
+    }
+
+    #[async_std::test]
+    async fn missing_steps_are_an_error() {
+        let (_registry, pipeline) = pipeline(&["auth", "nope", "echo"]);
+        assert!(pipeline.on_msg(request(true).into()).await.is_err());
+    }
+}
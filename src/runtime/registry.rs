@@ -1,6 +1,19 @@
-use super::VluginDef;
-use crate::Vlugin;
-use alloc::{borrow::ToOwned, rc::Rc, string::String};
+use super::{
+    middleware::{self, Middlewares},
+    VluginDef,
+};
+use crate::{
+    async_trait, http, http::Method, Answer, Error, Message, Messenger, Params, Socket, Vlugin,
+};
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    format,
+    rc::{Rc, Weak},
+    string::String,
+    vec::Vec,
+};
+use core::cell::RefCell;
 use hashbrown::HashMap;
 use path_tree::PathTree;
 
@@ -9,22 +22,68 @@ type PluginHandler = (VluginDef, Rc<dyn Vlugin>);
 /// Plugin to keep track of registered plugins
 pub(crate) struct PluginRegistry {
     pub(self) plugins: HashMap<String, PluginHandler>,
-    routes: PathTree<String>,
+    // routes of every host pattern, plugins without a host are under `None`
+    routes: HashMap<Option<String>, PathTree<(String, Vec<Method>)>>,
+    // open WebSockets and the plugin that accepted them
+    sockets: HashMap<String, (Option<String>, Rc<dyn Socket>)>,
+    // run around the plugins messages are sent to
+    pub middlewares: Middlewares,
+    // collected for every plugin when the runtime has metrics enabled
+    #[cfg(feature = "std")]
+    pub metrics: Option<Rc<super::metrics::Metrics>>,
 }
 
 #[derive(Debug)]
-pub(crate) struct RegistrationError;
+pub(crate) enum RegistrationError {
+    AlreadyRegistered,
+    NotRegistered,
+}
 
 impl PluginRegistry {
     pub fn new() -> Self {
         PluginRegistry {
             plugins: HashMap::new(),
-            routes: PathTree::new(),
+            routes: HashMap::new(),
+            sockets: HashMap::new(),
+            middlewares: Middlewares::default(),
+            #[cfg(feature = "std")]
+            metrics: None,
         }
     }
 
-    pub fn match_vlugin(&self, path: &str) -> Option<PluginHandler> {
-        let (name, _) = self.routes.find(path)?;
+    /// Finds the plugin handling `path`, the params captured by its route
+    /// and the methods the route allows(any if empty). Plugins mounted on the
+    /// given `host` are preferred over the ones mounted on any of its domains
+    /// and those over the ones mounted on every host
+    pub fn match_vlugin(
+        &self,
+        host: Option<&str>,
+        path: &str,
+    ) -> Option<(PluginHandler, Params, Vec<Method>)> {
+        let host = host.map(host_name).unwrap_or_default();
+        let mut wildcards = self
+            .routes
+            .iter()
+            .filter_map(|(pattern, routes)| {
+                let domain = pattern.as_deref()?.strip_prefix('*')?;
+                host.ends_with(domain).then_some((domain.len(), routes))
+            })
+            .collect::<Vec<_>>();
+        wildcards.sort_by_key(|(len, _)| core::cmp::Reverse(*len));
+
+        let ((name, methods), params) = self
+            .routes
+            .get(&Some(host.clone()))
+            .into_iter()
+            .chain(wildcards.into_iter().map(|(_, routes)| routes))
+            .chain(self.routes.get(&None))
+            .find_map(|routes| routes.find(path))?;
+        let (plugin, handler) = self.plugins.get(name)?;
+        let params = params.into_iter().collect();
+        Some(((plugin.clone(), handler.clone()), params, methods.clone()))
+    }
+
+    pub fn get(&self, name: &str) -> Option<PluginHandler> {
         let (plugin, handler) = self.plugins.get(name)?;
         Some((plugin.clone(), handler.clone()))
     }
@@ -35,34 +94,260 @@ impl PluginRegistry {
         handler: H,
     ) -> Result<(), RegistrationError> {
         if self.plugins.contains_key(&plugin.name) {
-            return Err(RegistrationError);
+            return Err(RegistrationError::AlreadyRegistered);
         }
-        let prefix = "/".to_owned() + plugin.prefix_or_name();
+        self.add_routes(&plugin);
+        self.plugins
+            .insert(plugin.name.clone(), (plugin, Rc::new(handler)));
+        Ok(())
+    }
+
+    /// Removes the plugin and its routes, in-flight requests keep
+    /// their own reference to the handler until they are done
+    pub fn unregister(&mut self, name: &str) -> Result<PluginHandler, RegistrationError> {
+        let removed = self
+            .plugins
+            .remove(name)
+            .ok_or(RegistrationError::NotRegistered)?;
+        self.rebuild_routes();
+        Ok(removed)
+    }
 
-        self.routes.insert(&prefix, plugin.name.clone());
-        self.routes.insert(&(prefix + "/*"), plugin.name.clone());
+    /// Removes every plugin, e.g. when the runtime stops
+    pub fn unregister_all(&mut self) -> Vec<PluginHandler> {
+        self.routes = HashMap::new();
+        self.plugins.drain().map(|(_, plugin)| plugin).collect()
+    }
+
+    /// Swaps the plugin registered as `name` with a new definition and handler
+    /// in a single step so there is no moment where requests can't be matched
+    pub fn replace<H: Vlugin + 'static>(
+        &mut self,
+        name: &str,
+        plugin: VluginDef,
+        handler: H,
+    ) -> Result<PluginHandler, RegistrationError> {
+        if !self.plugins.contains_key(name) {
+            return Err(RegistrationError::NotRegistered);
+        }
+        if plugin.name != name && self.plugins.contains_key(&plugin.name) {
+            return Err(RegistrationError::AlreadyRegistered);
+        }
+        let replaced = self.plugins.remove(name).expect("plugin exists");
         self.plugins
             .insert(plugin.name.clone(), (plugin, Rc::new(handler)));
+        self.rebuild_routes();
+        Ok(replaced)
+    }
+
+    fn add_routes(&mut self, plugin: &VluginDef) {
+        let prefix = "/".to_owned() + plugin.prefix_or_name();
+        let name = &plugin.name;
+        let host = plugin.host.as_deref().map(host_name);
+        let tree = self.routes.entry(host).or_default();
+        if plugin.routes.is_empty() {
+            tree.insert(&prefix, (name.clone(), Vec::new()));
+            tree.insert(&(prefix + "/*"), (name.clone(), Vec::new()));
+            return;
+        }
+        // the same path can be declared more than once with different methods
+        let mut routes = Vec::<(String, Vec<Method>)>::new();
+        for route in &plugin.routes {
+            let path = match route.path.trim_matches('/') {
+                "" => prefix.clone(),
+                path => prefix.trim_end_matches('/').to_owned() + "/" + path,
+            };
+            match routes.iter_mut().find(|(p, _)| *p == path) {
+                // an empty list allows any method
+                Some((_, methods)) if methods.is_empty() => {}
+                Some((_, methods)) if route.methods.is_empty() => methods.clear(),
+                Some((_, methods)) => {
+                    for method in &route.methods {
+                        if !methods.contains(method) {
+                            methods.push(*method);
+                        }
+                    }
+                }
+                None => routes.push((path, route.methods.clone())),
+            }
+        }
+        for (path, methods) in routes {
+            tree.insert(&path, (name.clone(), methods));
+        }
+    }
+
+    // PathTree doesn't support removing routes so we start from scratch
+    fn rebuild_routes(&mut self) {
+        self.routes = HashMap::new();
+        let plugins = self.plugins.values().map(|(p, _)| p.clone());
+        for plugin in plugins.collect::<alloc::vec::Vec<_>>() {
+            self.add_routes(&plugin);
+        }
+    }
+
+    /// Every registered plugin
+    pub fn all(&self) -> Vec<PluginHandler> {
+        self.plugins
+            .values()
+            .map(|(plugin, handler)| (plugin.clone(), handler.clone()))
+            .collect()
+    }
+
+    /// Whether a pipeline with the given steps would run `name`, directly or
+    /// through the steps of the registered pipelines it runs
+    pub fn leads_to(&self, steps: &[String], name: &str) -> bool {
+        let mut pending = steps.iter().collect::<Vec<_>>();
+        let mut seen = Vec::new();
+        while let Some(step) = pending.pop() {
+            if step == name {
+                return true;
+            }
+            if seen.contains(&step) {
+                continue;
+            }
+            seen.push(step);
+            if let Some((plugin, _)) = self.plugins.get(step) {
+                if let super::VluginType::Pipeline { steps } = &plugin.r#type {
+                    pending.extend(steps);
+                }
+            }
+        }
+        false
+    }
+
+    /// Plugins subscribed to events of the given topic
+    pub fn subscribers(&self, topic: &str) -> Vec<Rc<dyn Vlugin>> {
+        self.plugins
+            .values()
+            .filter(|(plugin, _)| plugin.subscriptions.iter().any(|t| t == topic))
+            .map(|(_, handler)| handler.clone())
+            .collect()
+    }
+
+    /// Plugins with a schedule that is due at the given `timestamp`
+    pub fn scheduled(&self, timestamp: u64) -> Vec<Rc<dyn Vlugin>> {
+        self.plugins
+            .values()
+            .filter(|(plugin, _)| matches!(&plugin.schedule, Some(s) if s.is_due(timestamp)))
+            .map(|(_, handler)| handler.clone())
+            .collect()
+    }
+
+    /// Keeps a WebSocket that is about to be opened so plugins can write to it,
+    /// the id of an open socket can't be taken by another one
+    pub fn add_socket(
+        &mut self,
+        id: &str,
+        socket: Rc<dyn Socket>,
+    ) -> Result<(), RegistrationError> {
+        if self.sockets.contains_key(id) {
+            return Err(RegistrationError::AlreadyRegistered);
+        }
+        self.sockets.insert(id.into(), (None, socket));
         Ok(())
     }
 
+    /// The WebSocket was accepted by `plugin` that gets its messages from now on
+    pub fn bind_socket(&mut self, id: &str, plugin: &str) {
+        if let Some((bound, _)) = self.sockets.get_mut(id) {
+            bound.replace(plugin.into());
+        }
+    }
+
+    pub fn remove_socket(&mut self, id: &str) -> Option<Rc<dyn Socket>> {
+        self.sockets.remove(id).map(|(_, socket)| socket)
+    }
+
+    pub fn socket(&self, id: &str) -> Option<Rc<dyn Socket>> {
+        self.sockets.get(id).map(|(_, socket)| socket.clone())
+    }
+
+    pub fn sockets(&self) -> Vec<Rc<dyn Socket>> {
+        self.sockets
+            .values()
+            .map(|(_, socket)| socket.clone())
+            .collect()
+    }
+
+    /// Plugin that accepted the WebSocket
+    pub fn socket_handler(&self, id: &str) -> Option<Rc<dyn Vlugin>> {
+        let (plugin, _) = self.sockets.get(id)?;
+        self.get(plugin.as_ref()?).map(|(_, handler)| handler)
+    }
+
+    /// Lets vlugins send messages to the plugins of the registry
+    pub fn messenger(registry: &Rc<RefCell<Self>>) -> Rc<dyn Messenger> {
+        Rc::new(RegistryMessenger(Rc::downgrade(registry)))
+    }
+
     #[cfg(feature = "serde")]
     pub fn get_handler<L: super::Loader>(
-        registry: Rc<core::cell::RefCell<Self>>,
+        registry: Rc<RefCell<Self>>,
         loader: Rc<L>,
     ) -> impl crate::Vlugin {
         RegistryHandler { registry, loader }
     }
 }
 
-#[cfg(feature = "serde")]
-use alloc::boxed::Box;
+// plugins keep their messenger so it can't keep the registry alive
+struct RegistryMessenger(Weak<RefCell<PluginRegistry>>);
+
+#[async_trait(?Send)]
+impl Messenger for RegistryMessenger {
+    /// Messages between plugins go through the middlewares of the receiver like requests
+    async fn send(&self, to: &str, msg: Message) -> Result<Answer, Error> {
+        let registry = self.0.upgrade().ok_or(Error::NotSupported)?;
+        let (plugin, handler) = registry.borrow().get(to).ok_or_else(|| {
+            let msg = format!("{} not found", to);
+            http::Error::from_str(http::StatusCode::NotFound, msg)
+        })?;
+        let middlewares = registry.borrow().middlewares.for_plugin(&plugin)?;
+        middleware::run(&middlewares, &plugin, msg, |msg| handler.on_msg(msg)).await
+    }
+
+    async fn publish(&self, topic: &str, payload: serde_json::Value) {
+        let subscribers = match self.0.upgrade() {
+            Some(registry) => registry.borrow().subscribers(topic),
+            None => return,
+        };
+        for subscriber in subscribers {
+            let event = Message::Event {
+                topic: topic.into(),
+                payload: payload.clone(),
+            };
+            let _ = subscriber.on_msg(event).await;
+        }
+    }
+
+    fn socket(&self, id: &str) -> Option<Rc<dyn Socket>> {
+        self.0.upgrade()?.borrow().socket(id)
+    }
+}
+
+/// Hosts are matched ignoring case and port
+fn host_name(host: &str) -> String {
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    host.to_ascii_lowercase()
+}
+
 #[cfg(feature = "serde")]
 struct RegistryHandler<L> {
-    registry: Rc<core::cell::RefCell<PluginRegistry>>,
+    registry: Rc<RefCell<PluginRegistry>>,
     loader: Rc<L>,
 }
 
+#[cfg(feature = "serde")]
+impl<L: super::Loader> RegistryHandler<L> {
+    async fn instantiate(&self, plugin: &VluginDef) -> Result<Box<dyn Vlugin>, crate::Error> {
+        let mut handler = super::create(&self.registry, &*self.loader, plugin).await?;
+        handler.link(PluginRegistry::messenger(&self.registry));
+        Ok(handler)
+    }
+}
+
 #[cfg(feature = "serde")]
 #[async_trait::async_trait(?Send)]
 impl<L> crate::Vlugin for RegistryHandler<L>
@@ -79,9 +364,20 @@ where
 
         let mut request = match msg {
             Message::Http(req) => req,
-            Message::Ping => return Err(crate::Error::NotSupported),
+            Message::Health => return Ok(crate::Answer::Pong),
+            _ => return Err(crate::Error::NotSupported),
         };
 
+        let name = request.url().path().trim_matches('/').to_owned();
+        // plugins that come with the runtime like the registry itself stay
+        let built_in = matches!(
+            self.registry.borrow().plugins.get(&name),
+            Some((plugin, _)) if plugin.r#type == super::VluginType::Static
+        );
+        if built_in && matches!(request.method(), Put | Delete) {
+            return Err(Error::from_str(StatusCode::Forbidden, name + " is built in").into());
+        }
+
         match request.method() {
             Get => {
                 let reg = self.registry.borrow();
@@ -95,17 +391,60 @@ where
                     .map_err(|e| Error::new(StatusCode::InternalServerError, e).into())
             }
             Post => {
-                let mut plugin: VluginDef = request.body_json().await?;
+                let plugin: VluginDef = request.body_json().await?;
                 let name = plugin.name.clone();
-                let factory = self.loader.load(&plugin).await?;
-                let handler = factory(plugin.config.take()).await?;
+                let handler = self.instantiate(&plugin).await?;
                 self.registry
                     .borrow_mut()
                     .register(plugin, handler)
-                    .map_err(|_| Error::from_str(StatusCode::Conflict, name + " already exists"))?;
+                    .map_err(|_| {
+                        Error::from_str(StatusCode::Conflict, name.clone() + " already exists")
+                    })?;
+                PluginRegistry::messenger(&self.registry)
+                    .publish(
+                        super::events::PLUGIN_LOADED,
+                        serde_json::json!({ "name": name }),
+                    )
+                    .await;
                 let res: Response = StatusCode::Created.into();
                 Ok(res.into())
             }
+            Put if !name.is_empty() => {
+                let plugin: VluginDef = request.body_json().await?;
+                if plugin.name != name {
+                    return Err(Error::from_str(
+                        StatusCode::BadRequest,
+                        "Plugin name doesn't match the URL",
+                    )
+                    .into());
+                }
+                if !self.registry.borrow().plugins.contains_key(&name) {
+                    return Err(Error::from_str(StatusCode::NotFound, name + " not found").into());
+                }
+                let handler = self.instantiate(&plugin).await?;
+                let (_, replaced) = self
+                    .registry
+                    .borrow_mut()
+                    .replace(&name, plugin, handler)
+                    .map_err(|_| Error::from_str(StatusCode::NotFound, name + " not found"))?;
+                let _ = replaced.on_destroy().await;
+                let res: Response = StatusCode::Ok.into();
+                Ok(res.into())
+            }
+            Delete if !name.is_empty() => {
+                let (_, removed) = self.registry.borrow_mut().unregister(&name).map_err(|_| {
+                    Error::from_str(StatusCode::NotFound, name.clone() + " not found")
+                })?;
+                let _ = removed.on_destroy().await;
+                PluginRegistry::messenger(&self.registry)
+                    .publish(
+                        super::events::PLUGIN_UNLOADED,
+                        serde_json::json!({ "name": name }),
+                    )
+                    .await;
+                let res: Response = StatusCode::NoContent.into();
+                Ok(res.into())
+            }
             _ => {
                 let res: Response = StatusCode::MethodNotAllowed.into();
                 Ok(res.into())
@@ -113,6 +452,8 @@ where
         }
     }
 
+    fn link(&mut self, _messenger: Rc<dyn Messenger>) {}
+
     fn context(&self) -> &crate::Context {
         unimplemented!()
     }
@@ -138,7 +479,7 @@ mod tests {
     fn match_with_leading_slash() {
         let mut registry = PluginRegistry::new();
         registry.register("foo".into(), ()).unwrap();
-        let handler = registry.match_vlugin("/_foo/");
+        let handler = registry.match_vlugin(None, "/_foo/");
         assert!(handler.is_some());
     }
 
@@ -146,7 +487,7 @@ mod tests {
     fn match_without_leading_slash() {
         let mut registry = PluginRegistry::new();
         registry.register("foo".into(), ()).unwrap();
-        let handler = registry.match_vlugin("/_foo");
+        let handler = registry.match_vlugin(None, "/_foo");
         assert!(handler.is_some());
     }
 
@@ -154,11 +495,106 @@ mod tests {
     fn match_all_after_prefix() {
         let mut registry = PluginRegistry::new();
         registry.register("foo".into(), ()).unwrap();
-        let handler = registry.match_vlugin("/_foo/bar");
+        let handler = registry.match_vlugin(None, "/_foo/bar");
         assert!(handler.is_some());
-        let handler = registry.match_vlugin("/_foo/bar/");
+        let handler = registry.match_vlugin(None, "/_foo/bar/");
         assert!(handler.is_some());
-        let handler = registry.match_vlugin("/_foo/bar/baz");
+        let handler = registry.match_vlugin(None, "/_foo/bar/baz");
         assert!(handler.is_some());
     }
+
+    #[test]
+    fn match_route_patterns() {
+        let mut registry = PluginRegistry::new();
+        let mut plugin: VluginDef = ("users", "users").into();
+        plugin.routes = vec!["/".into(), "/:id".into(), "/:id/posts/*".into()];
+        registry.register(plugin, ()).unwrap();
+
+        assert!(registry.match_vlugin(None, "/users").is_some());
+        let (_, params, _) = registry.match_vlugin(None, "/users/1").unwrap();
+        assert_eq!(params.get("id"), Some("1"));
+        let (_, params, _) = registry
+            .match_vlugin(None, "/users/1/posts/2021/hello")
+            .unwrap();
+        assert_eq!(params.get("id"), Some("1"));
+        assert!(registry.match_vlugin(None, "/users/1/comments").is_none());
+    }
+
+    #[test]
+    fn merge_methods_of_the_same_route() {
+        use crate::http::Method::*;
+        let mut registry = PluginRegistry::new();
+        let mut plugin: VluginDef = ("users", "users").into();
+        plugin.routes = vec![
+            ("/:id", &[Get][..]).into(),
+            ("/:id", &[Put, Get][..]).into(),
+            ("/", &[Get][..]).into(),
+            "/".into(),
+        ];
+        registry.register(plugin, ()).unwrap();
+
+        let (_, _, methods) = registry.match_vlugin(None, "/users/1").unwrap();
+        assert_eq!(methods, [Get, Put]);
+        let (_, _, methods) = registry.match_vlugin(None, "/users").unwrap();
+        assert!(methods.is_empty());
+    }
+
+    #[test]
+    fn match_plugins_by_host() {
+        let mut registry = PluginRegistry::new();
+        let mut mount = |name, host: Option<&str>| {
+            let mut plugin: VluginDef = (name, "api").into();
+            plugin.host = host.map(Into::into);
+            registry.register(plugin, ()).unwrap();
+        };
+        mount("any", None);
+        mount("foo", Some("foo.com"));
+        mount("all_foo", Some("*.foo.com"));
+        mount("bar_foo", Some("*.bar.foo.com"));
+
+        let matched = |host| {
+            let ((plugin, _), _, _) = registry.match_vlugin(host, "/api/users").unwrap();
+            plugin.name
+        };
+        assert_eq!(matched(Some("FOO.com:8080")), "foo");
+        assert_eq!(matched(Some("a.foo.com")), "all_foo");
+        assert_eq!(matched(Some("a.bar.foo.com")), "bar_foo");
+        assert_eq!(matched(Some("example.com")), "any");
+        assert_eq!(matched(None), "any");
+    }
+
+    #[test]
+    fn unregister_removes_routes() {
+        let mut registry = PluginRegistry::new();
+        registry.register("foo".into(), ()).unwrap();
+        registry.register("bar".into(), ()).unwrap();
+        registry.unregister("foo").unwrap();
+        assert!(registry.match_vlugin(None, "/_foo/baz").is_none());
+        assert!(registry.match_vlugin(None, "/_bar/baz").is_some());
+        assert!(registry.unregister("foo").is_err());
+    }
+
+    #[test]
+    fn replace_swaps_plugin_and_routes() {
+        let mut registry = PluginRegistry::new();
+        registry.register("foo".into(), ()).unwrap();
+        let (old, _) = registry
+            .replace("foo", ("foo", "new_foo").into(), ())
+            .unwrap();
+        assert_eq!(old.prefix_or_name(), "_foo");
+        assert!(registry.match_vlugin(None, "/_foo/bar").is_none());
+        let ((plugin, _), _, _) = registry.match_vlugin(None, "/new_foo/bar").unwrap();
+        assert_eq!(plugin.name, "foo");
+    }
+
+    #[test]
+    fn replace_requires_registered_plugin() {
+        let mut registry = PluginRegistry::new();
+        registry.register("foo".into(), ()).unwrap();
+        assert!(registry.replace("bar", "bar".into(), ()).is_err());
+        assert!(registry.replace("foo", "foo".into(), ()).is_ok());
+        registry.register("bar".into(), ()).unwrap();
+        assert!(registry.replace("foo", "bar".into(), ()).is_err());
+        assert_eq!(registry.plugins.len(), 2);
+    }
 }
@@ -0,0 +1,218 @@
+use alloc::string::String;
+use core::{convert::TryFrom, fmt, str::FromStr};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Cron-like schedule with the usual five fields `minute hour day month weekday`,
+/// fields can be a `*`, numbers, ranges or lists with an optional step
+/// like `*/15 8-18 * * 1,3,5`. The `@hourly`, `@daily`, `@weekly`, `@monthly` and
+/// `@yearly` shortcuts are supported too. Times are in UTC.
+///
+/// ```
+/// # use valor_core::runtime::Schedule;
+/// let schedule: Schedule = "*/15 * * * *".parse().unwrap();
+/// assert!(schedule.is_due(15 * 60));
+/// assert!(!schedule.is_due(16 * 60));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct Schedule {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // like cron, when both days and weekdays are restricted either of them can match
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// Checks if the minute of the given unix `timestamp` is one of the schedule
+    pub fn is_due(&self, timestamp: u64) -> bool {
+        let days = timestamp / 86_400;
+        let secs = timestamp % 86_400;
+        let (_, month, day) = civil_from_days(days);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4) % 7;
+        let is_set = |field: u64, n: u64| field & (1 << n) != 0;
+
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => is_set(self.days, day),
+            (true, false) => is_set(self.weekdays, weekday),
+            (false, false) => is_set(self.days, day) || is_set(self.weekdays, weekday),
+        };
+        is_set(self.minutes, secs % 3600 / 60)
+            && is_set(self.hours, secs / 3600)
+            && is_set(self.months, month)
+            && day_matches
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = InvalidSchedule;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let fields = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            fields => fields,
+        };
+        let mut fields = fields.split_whitespace();
+        let mut next = |min, max| parse_field(fields.next().ok_or(InvalidSchedule)?, min, max);
+        let (minutes, hours, days, months, weekdays) = (
+            next(0, 59)?,
+            next(0, 23)?,
+            next(1, 31)?,
+            next(1, 12)?,
+            next(0, 7)?,
+        );
+        if fields.next().is_some() {
+            return Err(InvalidSchedule);
+        }
+        let all = |min, max| parse_field("*", min, max).expect("valid field");
+        Ok(Schedule {
+            expr: expr.into(),
+            minutes,
+            hours,
+            days,
+            months,
+            // 7 is also Sunday
+            weekdays: (weekdays | weekdays >> 7) & all(0, 6),
+            any_day: days == all(1, 31),
+            any_weekday: weekdays == all(0, 7),
+        })
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = InvalidSchedule;
+
+    fn try_from(expr: String) -> Result<Self, Self::Error> {
+        expr.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.expr
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expr)
+    }
+}
+
+/// The schedule expression couldn't be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSchedule;
+
+impl fmt::Display for InvalidSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid schedule")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidSchedule {}
+
+// bit set with the values allowed by a field
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, InvalidSchedule> {
+    let num = |n: &str| n.parse::<u64>().map_err(|_| InvalidSchedule);
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, num(step)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (num(start)?, num(end)?),
+            // like `5/10`, from 5 to the end in steps of 10
+            None if part.contains('/') => (num(range)?, max),
+            None => (num(range)?, num(range)?),
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(InvalidSchedule);
+        }
+        for n in (start..=end).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+/// Year, month and day of the days since 1970-01-01, see
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2021-03-01 09:30 UTC, a Monday
+    const MONDAY: u64 = 1_614_591_000;
+    const MINUTE: u64 = 60;
+    const DAY: u64 = 86_400;
+
+    #[test]
+    fn convert_timestamps_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(MONDAY / DAY), (2021, 3, 1));
+        assert_eq!(civil_from_days(MONDAY / DAY - 1), (2021, 2, 28));
+    }
+
+    #[test]
+    fn match_schedules() {
+        let due = |expr: &str, timestamp| expr.parse::<Schedule>().unwrap().is_due(timestamp);
+        assert!(due("* * * * *", MONDAY));
+        assert!(due("30 9 * * *", MONDAY));
+        assert!(!due("30 9 * * *", MONDAY + MINUTE));
+        assert!(due("*/10 8-18 * 3 1-5", MONDAY));
+        assert!(!due("*/10 8-18 * 3 1-5", MONDAY - 2 * DAY));
+        assert!(due("30 9 * * 7", MONDAY - DAY));
+        // day or weekday when both are set
+        assert!(due("30 9 15 * 1", MONDAY));
+        assert!(due("30 9 1 * 5", MONDAY));
+        assert!(!due("30 9 15 * 5", MONDAY));
+        assert!(due("@daily", MONDAY - 9 * 3600 - 30 * MINUTE));
+        assert!(!due("@hourly", MONDAY));
+    }
+
+    #[test]
+    fn reject_invalid_schedules() {
+        for expr in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert_eq!(expr.parse::<Schedule>(), Err(InvalidSchedule), "{}", expr);
+        }
+    }
+}
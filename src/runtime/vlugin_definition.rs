@@ -1,5 +1,6 @@
-use crate::VluginConfig;
-use alloc::{borrow::ToOwned, string::String};
+use super::Schedule;
+use crate::{http::Method, VluginConfig};
+use alloc::{borrow::ToOwned, collections::BTreeMap, string::String, vec::Vec};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
@@ -12,11 +13,45 @@ pub struct VluginDef {
     /// Url prefix where the plugin is mounted, defaults to the name
     #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     pub prefix: Option<String>,
+    /// Host the plugin is mounted on, e.g. `example.com` or `*.example.com` for any of its
+    /// subdomains. Plugins without a host are available on every host
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub host: Option<String>,
+    /// Route patterns relative to the prefix like `/:id/posts/*`, the params they capture are
+    /// passed along with the request. Every path under the prefix is matched when empty
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub routes: Vec<Route>,
+    /// Names of the middlewares to run around the plugin, after the global ones
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub middlewares: Vec<String>,
+    /// Topics of the events the plugin wants to receive
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub subscriptions: Vec<String>,
+    /// When the plugin gets a `Tick` message, e.g. `*/5 * * * *` for every 5 minutes
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub schedule: Option<Schedule>,
+    /// Optional plugins are reported when they fail their health check but the runtime
+    /// is still considered ready
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "core::ops::Not::not")
+    )]
+    pub optional: bool,
     /// What kind of plugin
     #[cfg_attr(feature = "serde", serde(flatten))]
     pub r#type: VluginType,
-    /// Environment configuration to pass down to the plugin instance
-    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    /// Environment configuration to pass down to the plugin instance,
+    /// it's never serialized back as it can hold secrets
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing))]
     pub config: Option<VluginConfig>, // NOTE this makes the core dependent on serde
 }
 
@@ -29,6 +64,67 @@ impl VluginDef {
     }
 }
 
+/// Path pattern of a route and the HTTP methods it accepts, routes that accept
+/// any method can be written as a plain string, e.g. `"/:id"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(from = "RouteDef", into = "RouteDef")
+)]
+pub struct Route {
+    pub path: String,
+    /// Allowed methods, any method is allowed when empty
+    pub methods: Vec<Method>,
+}
+
+impl From<&str> for Route {
+    fn from(path: &str) -> Self {
+        (path, &[][..]).into()
+    }
+}
+
+impl From<(&str, &[Method])> for Route {
+    fn from((path, methods): (&str, &[Method])) -> Self {
+        Route {
+            path: path.into(),
+            methods: methods.into(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RouteDef {
+    Path(String),
+    WithMethods { path: String, methods: Vec<Method> },
+}
+
+#[cfg(feature = "serde")]
+impl From<RouteDef> for Route {
+    fn from(route: RouteDef) -> Self {
+        match route {
+            RouteDef::Path(path) => Route {
+                path,
+                methods: Vec::new(),
+            },
+            RouteDef::WithMethods { path, methods } => Route { path, methods },
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<Route> for RouteDef {
+    fn from(Route { path, methods }: Route) -> Self {
+        if methods.is_empty() {
+            RouteDef::Path(path)
+        } else {
+            RouteDef::WithMethods { path, methods }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(
     feature = "serde",
@@ -45,6 +141,49 @@ pub enum VluginType {
     },
     /// Web script or WASM
     Web { url: String },
+    /// WASI module that can only access the directories and environment it's given
+    Wasi {
+        path: String,
+        /// Directories the module can access, as seen by the module mapped to the host directory
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "BTreeMap::is_empty")
+        )]
+        preopens: BTreeMap<String, Preopen>,
+        /// Environment variables visible to the module
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "BTreeMap::is_empty")
+        )]
+        env: BTreeMap<String, String>,
+    },
+    /// Chain of registered plugins that handle a message one after the other
+    Pipeline { steps: Vec<String> },
+}
+
+/// Host directory of a WASI module, either just its path that the module can only read
+/// or e.g. `{ "path": "/var/lib/store", "writable": true }` to let the module change it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(untagged))]
+pub enum Preopen {
+    ReadOnly(String),
+    Dir {
+        path: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        writable: bool,
+    },
+}
+
+impl Preopen {
+    pub fn path(&self) -> &str {
+        match self {
+            Preopen::ReadOnly(path) | Preopen::Dir { path, .. } => path,
+        }
+    }
+
+    pub fn is_writable(&self) -> bool {
+        matches!(self, Preopen::Dir { writable: true, .. })
+    }
 }
 
 impl From<&str> for VluginDef {
@@ -52,6 +191,12 @@ impl From<&str> for VluginDef {
         VluginDef {
             name: name.into(),
             prefix: Some("_".to_owned() + name),
+            host: None,
+            routes: Vec::new(),
+            middlewares: Vec::new(),
+            subscriptions: Vec::new(),
+            schedule: None,
+            optional: false,
             r#type: VluginType::Static,
             config: None,
         }
@@ -63,6 +208,12 @@ impl From<(&str, &str)> for VluginDef {
         VluginDef {
             name: name.into(),
             prefix: Some(prefix.into()),
+            host: None,
+            routes: Vec::new(),
+            middlewares: Vec::new(),
+            subscriptions: Vec::new(),
+            schedule: None,
+            optional: false,
             r#type: VluginType::Static,
             config: None,
         }
@@ -0,0 +1,202 @@
+//! Server-Sent Events, responses that stay open so vlugins can push
+//! updates to the client as they happen.
+use crate::{http, Error};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use async_channel::Receiver;
+use core::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_lite::{io, ready, AsyncBufRead, AsyncRead, Stream};
+
+/// Events waiting for a slow client before senders have to wait
+const BUFFERED_EVENTS: usize = 16;
+
+/// Creates a `text/event-stream` response and the sender of its events,
+/// the stream ends when all senders are dropped
+pub fn channel() -> (Sender, http::Response) {
+    let (sender, events) = async_channel::bounded(BUFFERED_EVENTS);
+    let mut res = http::Response::new(http::StatusCode::Ok);
+    res.set_body(http::Body::from_reader(
+        EventStream {
+            events,
+            chunk: Vec::new(),
+            pos: 0,
+        },
+        None,
+    ));
+    res.set_content_type(http::mime::SSE);
+    res.insert_header("cache-control", "no-cache");
+    (Sender(sender), res)
+}
+
+/// Pushes events to the client of a stream created with `channel`
+#[derive(Debug, Clone)]
+pub struct Sender(async_channel::Sender<String>);
+
+impl Sender {
+    /// Sends an event, it fails once the client is gone
+    pub async fn send(&self, event: impl Into<Event>) -> Result<(), Error> {
+        self.push(event.into().to_string()).await
+    }
+
+    /// Sends a comment that clients ignore to keep idle connections open
+    pub async fn keep_alive(&self) -> Result<(), Error> {
+        self.push(":\n\n".into()).await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+
+    async fn push(&self, event: String) -> Result<(), Error> {
+        self.0
+            .send(event)
+            .await
+            .map_err(|_| http::Error::from_str(http::StatusCode::Gone, "Stream closed").into())
+    }
+}
+
+/// Update for the client, data with line breaks is sent in several `data` fields
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    data: String,
+    name: Option<String>,
+    id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Event {
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Type of the event clients listen to, `message` when not set
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Clients send the last id they got in `Last-Event-ID` when reconnecting
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Milliseconds clients wait before reconnecting
+    pub fn retry(mut self, ms: u64) -> Self {
+        self.retry = Some(ms);
+        self
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = &self.name {
+            writeln!(f, "event: {}", name)?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry)?;
+        }
+        for line in self.data.split('\n') {
+            writeln!(f, "data: {}", line.trim_end_matches('\r'))?;
+        }
+        writeln!(f)
+    }
+}
+
+impl From<String> for Event {
+    fn from(data: String) -> Self {
+        Event::new(data)
+    }
+}
+
+impl From<&str> for Event {
+    fn from(data: &str) -> Self {
+        Event::new(data)
+    }
+}
+
+/// Body of the response, it's read as events are sent
+struct EventStream {
+    events: Receiver<String>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for EventStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let chunk = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncBufRead for EventStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos == this.chunk.len() {
+            match ready!(Pin::new(&mut this.events).poll_next(cx)) {
+                Some(event) => {
+                    this.chunk = event.into_bytes();
+                    this.pos = 0;
+                }
+                None => return Poll::Ready(Ok(&[])),
+            }
+        }
+        Poll::Ready(Ok(&this.chunk[this.pos..]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn stream_events() -> Result<(), Error> {
+        let (events, mut res) = channel();
+        assert_eq!(res.content_type(), Some(http::mime::SSE));
+        assert_eq!(res.len(), None);
+
+        events.send("hello").await?;
+        events
+            .send(Event::new("multi\nline").name("update").id("2"))
+            .await?;
+        events.keep_alive().await?;
+        drop(events);
+
+        assert_eq!(
+            res.body_string().await?,
+            "data: hello\n\nevent: update\nid: 2\ndata: multi\ndata: line\n\n:\n\n"
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn sending_fails_when_client_is_gone() {
+        let (events, res) = channel();
+        drop(res);
+        assert!(events.is_closed());
+        assert!(events.send("hello").await.is_err());
+    }
+}
@@ -5,18 +5,32 @@ pub mod web {
     #[global_allocator]
     static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
-    use crate::http::{Request, Response};
-    use core::pin::Pin;
-    use js_sys::{Array, Uint8Array};
+    use crate::http::{Body, Request, Response};
+    use alloc::{boxed::Box, rc::Rc, string::ToString};
+    use core::{cell::RefCell, mem, pin::Pin};
+    use futures_lite::AsyncReadExt;
+    use js_sys::{Array, Function, Object, Promise, Reflect, Uint8Array};
     pub use wasm_bindgen;
-    use wasm_bindgen::JsCast;
+    use wasm_bindgen::{prelude::*, JsCast};
     pub use wasm_bindgen_futures;
-    use wasm_bindgen_futures::JsFuture;
+    use wasm_bindgen_futures::{future_to_promise, JsFuture};
     pub use web_sys;
     use web_sys::{
-        Headers, Request as JsRequest, RequestInit, Response as JsResponse, ResponseInit,
+        Headers, ReadableStream, Request as JsRequest, RequestInit, Response as JsResponse,
+        ResponseInit,
     };
 
+    const CHUNK_SIZE: usize = 8 * 1024;
+
+    #[wasm_bindgen]
+    extern "C" {
+        type StreamController;
+        #[wasm_bindgen(method)]
+        fn enqueue(this: &StreamController, chunk: &Uint8Array);
+        #[wasm_bindgen(method)]
+        fn close(this: &StreamController);
+    }
+
     // NOTE we might be able to remove this some day
     // https://github.com/http-rs/http-types/issues/317
     pub async fn into_js_request(mut req: Request) -> (JsRequest, Pin<alloc::vec::Vec<u8>>) {
@@ -70,7 +84,6 @@ pub mod web {
     pub async fn into_js_response(mut res: Response) -> JsResponse {
         let mut init = ResponseInit::new();
         init.status(res.status() as u16);
-        let mut body = res.body_bytes().await.unwrap();
 
         let headers = Headers::new().unwrap();
         for (name, value) in res.iter() {
@@ -80,9 +93,41 @@ pub mod web {
         }
         init.headers(&headers);
 
+        // bodies of unknown length are streamed to the browser as they are read
+        if res.len().is_none() {
+            let body = into_js_stream(res.take_body());
+            return JsResponse::new_with_opt_readable_stream_and_init(Some(&body), &init).unwrap();
+        }
+        let mut body = res.body_bytes().await.unwrap();
         JsResponse::new_with_opt_u8_array_and_init(Some(body.as_mut()), &init).unwrap()
     }
 
+    fn into_js_stream(body: Body) -> ReadableStream {
+        let body = Rc::new(RefCell::new(body));
+        let pull = Closure::wrap(Box::new(move |controller: StreamController| {
+            let body = body.clone();
+            future_to_promise(async move {
+                // the browser doesn't pull again before the previous pull is done
+                let mut reading = mem::replace(&mut *body.borrow_mut(), Body::empty());
+                let mut chunk = alloc::vec![0; CHUNK_SIZE];
+                let n = reading.read(&mut chunk).await;
+                *body.borrow_mut() = reading;
+                match n.map_err(|err| JsValue::from(err.to_string()))? {
+                    0 => controller.close(),
+                    n => controller.enqueue(&Uint8Array::from(&chunk[..n])),
+                }
+                Ok(JsValue::UNDEFINED)
+            })
+        }) as Box<dyn FnMut(StreamController) -> Promise>);
+
+        let source = Object::new();
+        Reflect::set(&source, &"pull".into(), &pull.into_js_value()).unwrap();
+        let stream = Reflect::get(&js_sys::global(), &"ReadableStream".into()).unwrap();
+        Reflect::construct(stream.unchecked_ref::<Function>(), &Array::of1(&source))
+            .expect("readable streams")
+            .unchecked_into()
+    }
+
     pub async fn into_response(res: JsResponse) -> Response {
         let body = JsFuture::from(res.array_buffer().unwrap()).await.unwrap();
         let body = Uint8Array::new(&body).to_vec();
@@ -173,6 +218,18 @@ pub mod web {
             assert_eq!(headers.get("x-foo").unwrap(), Some("foo132".to_string()));
         }
 
+        #[wasm_bindgen_test]
+        async fn stream_into_js_response() {
+            let mut res = Response::new(200);
+            let body = futures_lite::io::Cursor::new(REQ_BODY.repeat(1000));
+            res.set_body(Body::from_reader(body, None));
+
+            let res = into_js_response(res).await;
+
+            let body = JsFuture::from(res.text().unwrap()).await.unwrap();
+            assert_eq!(body, JsValue::from(REQ_BODY.repeat(1000)));
+        }
+
         fn test_request() -> Request {
             let mut req = Request::new(crate::Method::Post, REQ_URL);
             req.set_body(REQ_BODY);
@@ -1,17 +1,19 @@
 use crate::{async_trait, http, Error, VluginConfig};
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeMap, rc::Rc, string::String, vec::Vec};
 use core::{
     any::{Any, TypeId},
+    convert::TryFrom,
     marker::PhantomData,
 };
 use hashbrown::HashMap;
 
 /// Context allows plugins to pass state to the message handler
-/// and eventually to easily communicate with other plugins.
+/// and to communicate with other plugins.
 #[derive(Default)]
 pub struct Context {
     data: HashMap<TypeId, Box<dyn Any>>,
     conf: Option<VluginConfig>,
+    messenger: Option<Rc<dyn Messenger>>,
 }
 
 impl Context {
@@ -38,6 +40,48 @@ impl Context {
         self.conf.as_ref()
     }
 
+    pub fn with_messenger(&mut self, messenger: Rc<dyn Messenger>) {
+        self.messenger.replace(messenger);
+    }
+
+    /// Sends a message to the vlugin registered as `to` and waits for its answer,
+    /// it's only possible once the vlugin was registered in a runtime
+    pub async fn send(&self, to: &str, msg: impl Into<Message>) -> Result<Answer, Error> {
+        match &self.messenger {
+            Some(messenger) => messenger.send(to, msg.into()).await,
+            None => Err(Error::NotSupported),
+        }
+    }
+
+    /// Publishes an event to the vlugins subscribed to `topic`
+    pub async fn publish(&self, topic: &str, payload: serde_json::Value) -> Result<(), Error> {
+        match &self.messenger {
+            Some(messenger) => {
+                messenger.publish(topic, payload).await;
+                Ok(())
+            }
+            None => Err(Error::NotSupported),
+        }
+    }
+
+    /// Sends a frame to the WebSocket client connected with `id`
+    pub fn send_frame(&self, id: &str, frame: impl Into<Frame>) -> Result<(), Error> {
+        self.socket(id)?.send(frame.into())
+    }
+
+    /// Closes the WebSocket connected with `id`
+    pub fn close_socket(&self, id: &str) -> Result<(), Error> {
+        self.socket(id)?.close();
+        Ok(())
+    }
+
+    fn socket(&self, id: &str) -> Result<Rc<dyn Socket>, Error> {
+        let messenger = self.messenger.as_ref().ok_or(Error::NotSupported)?;
+        messenger.socket(id).ok_or_else(|| {
+            http::Error::from_str(http::StatusCode::NotFound, "Socket not found").into()
+        })
+    }
+
     #[cfg(feature = "serde")]
     pub fn config<'a, C>(&'a self) -> Option<C>
     where
@@ -49,6 +93,24 @@ impl Context {
     }
 }
 
+/// Delivers messages between vlugins, the runtime gives one to every vlugin it registers
+#[async_trait(?Send)]
+pub trait Messenger {
+    async fn send(&self, to: &str, msg: Message) -> Result<Answer, Error>;
+
+    /// Delivers an event to the vlugins subscribed to its topic, their answers are ignored
+    async fn publish(&self, topic: &str, payload: serde_json::Value);
+
+    /// Open WebSocket connection with the given id
+    fn socket(&self, id: &str) -> Option<Rc<dyn Socket>>;
+}
+
+/// Writing end of a WebSocket connection, implemented by the server holding the connection
+pub trait Socket {
+    fn send(&self, frame: Frame) -> Result<(), Error>;
+    fn close(&self);
+}
+
 /// The Vlugin trait defines plugins that can handle any supported message
 /// format. It also allows the plugin to initialize an internal state with the
 /// help of the `Context` type.
@@ -111,6 +173,18 @@ pub trait Vlugin {
     }
 
     async fn on_msg(&self, msg: Message) -> Result<Answer, Error>;
+
+    /// Hook to clean up before the runtime lets go of the vlugin(e.g. flush buffers or close
+    /// connections) when it's unregistered, replaced or the runtime stops. Messages that
+    /// were already being handled might still be in progress
+    async fn on_destroy(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called by the runtime when registering the vlugin so it can talk to other vlugins
+    fn link(&mut self, messenger: Rc<dyn Messenger>) {
+        self.context_mut().with_messenger(messenger);
+    }
 }
 
 #[async_trait(?Send)]
@@ -118,6 +192,10 @@ impl<T> Vlugin for Box<T>
 where
     T: Vlugin + ?Sized,
 {
+    fn link(&mut self, messenger: Rc<dyn Messenger>) {
+        (**self).link(messenger)
+    }
+
     async fn on_create(&mut self) -> Result<(), Error> {
         (&mut **self).on_create().await
     }
@@ -126,6 +204,10 @@ where
         (&**self).on_msg(msg).await
     }
 
+    async fn on_destroy(&self) -> Result<(), Error> {
+        (&**self).on_destroy().await
+    }
+
     fn context_mut(&mut self) -> &mut Context {
         (&mut **self).context_mut()
     }
@@ -138,7 +220,7 @@ where
 pub fn h<M, O, F, Fut>(handler_fn: F) -> FnHandler<M, O, F, Fut>
 where
     F: Fn(M, &Context) -> Fut,
-    M: From<Message>,
+    M: TryFrom<Message>,
     O: Into<Answer>,
     Fut: core::future::Future<Output = Result<O, Error>>,
 {
@@ -148,7 +230,7 @@ where
 pub struct FnHandler<M, O, F, Fut>(F, Context, PhantomData<(M, O, Fut)>)
 where
     F: Fn(M, &Context) -> Fut,
-    M: From<Message>,
+    M: TryFrom<Message>,
     O: Into<Answer>,
     Fut: core::future::Future<Output = Result<O, Error>>;
 
@@ -156,12 +238,19 @@ where
 impl<M, O, F, Fut> Vlugin for FnHandler<M, O, F, Fut>
 where
     F: Fn(M, &Context) -> Fut,
-    M: From<Message>,
+    M: TryFrom<Message>,
     O: Into<Answer>,
     Fut: core::future::Future<Output = Result<O, Error>>,
 {
     async fn on_msg(&self, msg: Message) -> Result<Answer, Error> {
-        Ok(self.0(M::from(msg), self.context()).await?.into())
+        // closures only take requests, they are healthy as long as they run
+        match msg {
+            Message::Http(_) => {}
+            Message::Ping | Message::Health => return Ok(Answer::Pong),
+            _ => return Err(Error::NotSupported),
+        }
+        let msg = M::try_from(msg).map_err(|_| Error::NotSupported)?;
+        Ok(self.0(msg, self.context()).await?.into())
     }
 
     fn context_mut(&mut self) -> &mut Context {
@@ -179,6 +268,8 @@ impl Vlugin for () {
         Ok(Answer::Pong)
     }
 
+    fn link(&mut self, _messenger: Rc<dyn Messenger>) {}
+
     fn context_mut(&mut self) -> &mut Context {
         unreachable!()
     }
@@ -192,6 +283,56 @@ impl Vlugin for () {
 pub enum Message {
     Http(http::Request),
     Ping,
+    /// Notification about something that happened, it doesn't expect an answer
+    Event {
+        topic: String,
+        payload: serde_json::Value,
+    },
+    /// Sent to plugins with a schedule when it's due, with the current unix timestamp
+    Tick(u64),
+    /// A client wants to open a WebSocket with the upgrade `request`, the plugin accepts
+    /// it answering `Answer::WsConnect` and gets the frames of the connection `id` after
+    WsConnect {
+        id: String,
+        request: http::Request,
+    },
+    /// Frame received from the WebSocket `id`
+    WsFrame {
+        id: String,
+        frame: Frame,
+    },
+    /// The WebSocket `id` was closed
+    WsClose {
+        id: String,
+    },
+    /// Health check, plugins answer with an error when they can't handle requests
+    /// (e.g. a database they depend on is unreachable)
+    Health,
+}
+
+/// Data sent over a WebSocket connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Frame {
+    fn from(text: String) -> Self {
+        Frame::Text(text)
+    }
+}
+
+impl From<&str> for Frame {
+    fn from(text: &str) -> Self {
+        Frame::Text(text.into())
+    }
+}
+
+impl From<Vec<u8>> for Frame {
+    fn from(data: Vec<u8>) -> Self {
+        Frame::Binary(data)
+    }
 }
 
 impl From<http::Request> for Message {
@@ -206,27 +347,74 @@ impl From<()> for Message {
     }
 }
 
-impl From<Message> for http::Request {
-    fn from(msg: Message) -> Self {
+/// Only HTTP messages are requests, any other message is given back
+impl TryFrom<Message> for http::Request {
+    type Error = Message;
+
+    fn try_from(msg: Message) -> Result<Self, Message> {
         match msg {
-            Message::Http(req) => req,
-            _ => unimplemented!(),
+            Message::Http(req) => Ok(req),
+            msg => Err(msg),
         }
     }
 }
 
+/// Parameters captured by the route pattern that matched a request,
+/// e.g. `id` for `/users/:id`. They are added to the request as an extension.
+///
+/// ```
+/// # use valor_core::{http, Params};
+/// # let mut req = http::Request::get("http://valor/users/1");
+/// # req.ext_mut().insert(vec![("id", "1")].into_iter().collect::<Params>());
+/// let id = req.ext().get::<Params>().and_then(|p| p.get("id"));
+/// assert_eq!(id, Some("1"));
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Params(BTreeMap<String, String>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> core::iter::FromIterator<(K, V)> for Params {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Params(
+            iter.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
 /// Type of valid outputs that a handler can return
 #[derive(Debug)]
 pub enum Answer {
     Http(http::Response),
     Pong,
+    /// Accepts a WebSocket connection
+    WsConnect,
+    /// Frame to send back to the WebSocket the message came from
+    WsFrame(Frame),
+    /// Closes the WebSocket the message came from
+    WsClose,
 }
 
 impl From<Answer> for http::Response {
     fn from(out: Answer) -> Self {
         match out {
             Answer::Http(res) => res,
-            Answer::Pong => http::StatusCode::Ok.into(),
+            Answer::WsConnect => http::StatusCode::SwitchingProtocols.into(),
+            _ => http::StatusCode::Ok.into(),
         }
     }
 }
@@ -244,6 +432,12 @@ impl From<http::Response> for Answer {
     }
 }
 
+impl From<Frame> for Answer {
+    fn from(frame: Frame) -> Self {
+        Answer::WsFrame(frame)
+    }
+}
+
 impl From<()> for Answer {
     fn from(_: ()) -> Self {
         Answer::Pong
@@ -0,0 +1,535 @@
+//! Access logs of the requests the server handles in the Common or Combined Log
+//! Format or as JSON lines, written to stdout or to a file rotated by size.
+use async_std::io::{self, BufRead, Read};
+use kv_log_macro::warn;
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use structopt::StructOpt;
+use valor::{
+    http::{self, headers},
+    runtime::civil_from_days,
+};
+
+/// Access log options of the command line, they take precedence over the config file
+#[derive(StructOpt, Debug)]
+pub(crate) struct Opt {
+    /// Where access logs are written, `stdout`, `off` or the path of a file
+    #[structopt(long = "access-log")]
+    target: Option<Target>,
+
+    /// Format of access logs, `common`, `combined` or `json`
+    #[structopt(long = "access-log-format")]
+    format: Option<Format>,
+}
+
+/// Access log section of the config file
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub(crate) struct Config {
+    target: Target,
+    format: Format,
+    /// Bytes a log file grows to before it's rotated
+    max_size: u64,
+    /// Rotated files kept next to the log, e.g. `access.log.1`
+    keep: usize,
+    /// Plugins whose requests are logged, all of them when empty
+    plugins: Vec<String>,
+    /// Plugins whose requests are never logged
+    skip: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            target: Target::Stdout,
+            format: Format::Combined,
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+            plugins: Vec::new(),
+            skip: vec!["health".into()],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+enum Target {
+    Stdout,
+    File(PathBuf),
+    Off,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        match target {
+            "" => Err("access log target can't be empty".into()),
+            "stdout" => Ok(Target::Stdout),
+            "off" => Ok(Target::Off),
+            path => Ok(Target::File(path.into())),
+        }
+    }
+}
+
+impl TryFrom<String> for Target {
+    type Error = String;
+
+    fn try_from(target: String) -> Result<Self, Self::Error> {
+        target.parse()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Common,
+    Combined,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "common" => Ok(Format::Common),
+            "combined" => Ok(Format::Combined),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "{} should be `common`, `combined` or `json`",
+                format
+            )),
+        }
+    }
+}
+
+pub(crate) struct AccessLog {
+    format: Format,
+    sink: Option<Mutex<Sink>>,
+    plugins: Vec<String>,
+    skip: Vec<String>,
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl AccessLog {
+    pub fn new(mut config: Config, opt: &Opt) -> io::Result<Self> {
+        config.target = opt.target.clone().unwrap_or(config.target);
+        config.format = opt.format.unwrap_or(config.format);
+        let sink = match config.target {
+            Target::Stdout => Some(Sink::Stdout),
+            Target::File(path) => Some(Sink::File(RotatingFile::open(
+                path,
+                config.max_size,
+                config.keep,
+            )?)),
+            Target::Off => None,
+        };
+        Ok(AccessLog {
+            format: config.format,
+            sink: sink.map(Mutex::new),
+            plugins: config.plugins,
+            skip: config.skip,
+        })
+    }
+
+    /// Takes note of the request before it's handled and counts the bytes read from its body
+    pub fn start(&self, req: &mut http::Request) -> Entry {
+        let req_bytes = Arc::new(AtomicU64::new(0));
+        if self.sink.is_some() {
+            let len = req.len();
+            let had_type = req.header(headers::CONTENT_TYPE).is_some();
+            let body = Counted {
+                body: req.take_body(),
+                bytes: req_bytes.clone(),
+                entry: None,
+            };
+            req.set_body(http::Body::from_reader(body, len));
+            if !had_type {
+                req.remove_header(headers::CONTENT_TYPE);
+            }
+        }
+        let header = |name| req.header(name).map(|h| h.as_str().to_owned());
+        Entry {
+            time: SystemTime::now(),
+            started: Instant::now(),
+            peer: req.peer_addr().map(|addr| addr.to_owned()),
+            method: req.method(),
+            target: match req.url().query() {
+                Some(query) => format!("{}?{}", req.url().path(), query),
+                None => req.url().path().into(),
+            },
+            version: req.version(),
+            referer: header(headers::REFERER),
+            user_agent: header(headers::USER_AGENT),
+            id: header(crate::REQ_ID_HEADER.into()).unwrap_or_default(),
+            req_bytes,
+            status: 0,
+            plugin: None,
+        }
+    }
+
+    /// Logs the request once the body of its response is sent, unless its plugin isn't logged
+    pub fn finish(self: &Arc<Self>, mut entry: Entry, res: &mut http::Response) {
+        entry.status = res.status().into();
+        entry.plugin = res.header("x-valor-plugin").map(|h| h.as_str().to_owned());
+        if !self.logs(entry.plugin.as_deref()) {
+            return;
+        }
+        let len = res.len();
+        let had_type = res.header(headers::CONTENT_TYPE).is_some();
+        let body = Counted {
+            body: res.take_body(),
+            bytes: Arc::new(AtomicU64::new(0)),
+            entry: Some((self.clone(), entry)),
+        };
+        res.set_body(http::Body::from_reader(body, len));
+        if !had_type {
+            res.remove_header(headers::CONTENT_TYPE);
+        }
+    }
+
+    fn logs(&self, plugin: Option<&str>) -> bool {
+        let listed = |list: &[String]| plugin.is_some_and(|p| list.iter().any(|l| l == p));
+        self.sink.is_some()
+            && !listed(&self.skip)
+            && (self.plugins.is_empty() || listed(&self.plugins))
+    }
+
+    fn write(&self, entry: &Entry, res_bytes: u64) {
+        let line = self.format.line(entry, res_bytes);
+        let mut sink = match self.sink.as_ref().map(|sink| sink.lock()) {
+            Some(Ok(sink)) => sink,
+            _ => return,
+        };
+        let written = match &mut *sink {
+            Sink::Stdout => writeln!(std::io::stdout().lock(), "{}", line),
+            Sink::File(file) => file.write_line(&line),
+        };
+        if let Err(err) = written {
+            warn!("can't write access log: {}", err);
+        }
+    }
+}
+
+/// What's logged about a request, completed with its response
+pub(crate) struct Entry {
+    time: SystemTime,
+    started: Instant,
+    peer: Option<String>,
+    method: http::Method,
+    target: String,
+    version: Option<http::Version>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    id: String,
+    req_bytes: Arc<AtomicU64>,
+    status: u16,
+    plugin: Option<String>,
+}
+
+impl Entry {
+    /// Address of the client without its port
+    fn host(&self) -> Option<String> {
+        let peer = self.peer.as_deref()?;
+        Some(match peer.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => peer.into(),
+        })
+    }
+}
+
+impl Format {
+    fn line(&self, entry: &Entry, res_bytes: u64) -> String {
+        let version = entry.version.map(|v| v.to_string());
+        let request = format!(
+            "{} {} {}",
+            entry.method,
+            entry.target,
+            version.as_deref().unwrap_or("HTTP/1.1")
+        );
+        let quoted = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+            None => "\"-\"".into(),
+        };
+        let common = || {
+            format!(
+                "{} - - [{}] {} {} {}",
+                entry.host().unwrap_or_else(|| "-".into()),
+                Utc::from(entry.time).clf(),
+                quoted(&Some(request.clone())),
+                entry.status,
+                if res_bytes == 0 {
+                    "-".into()
+                } else {
+                    res_bytes.to_string()
+                }
+            )
+        };
+        match self {
+            Format::Common => common(),
+            Format::Combined => format!(
+                "{} {} {}",
+                common(),
+                quoted(&entry.referer),
+                quoted(&entry.user_agent)
+            ),
+            Format::Json => serde_json::json!({
+                "time": Utc::from(entry.time).rfc3339(),
+                "id": entry.id,
+                "peer": entry.peer,
+                "method": entry.method.to_string(),
+                "target": entry.target,
+                "version": version,
+                "status": entry.status,
+                "plugin": entry.plugin,
+                "req_bytes": entry.req_bytes.load(Ordering::Relaxed),
+                "res_bytes": res_bytes,
+                "dur_ms": entry.started.elapsed().as_millis() as u64,
+                "referer": entry.referer,
+                "user_agent": entry.user_agent,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Body that counts the bytes read from it, a response body writes the entry
+/// of its request when it's dropped, i.e. once it's sent or the client is gone
+struct Counted {
+    body: http::Body,
+    bytes: Arc<AtomicU64>,
+    entry: Option<(Arc<AccessLog>, Entry)>,
+}
+
+impl Read for Counted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = Pin::new(&mut self.body).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = read {
+            self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        read
+    }
+}
+
+impl BufRead for Counted {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.bytes.fetch_add(amt as u64, Ordering::Relaxed);
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        if let Some((log, entry)) = self.entry.take() {
+            log.write(&entry, self.bytes.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Log file that's renamed to `<path>.1` when it gets too big, older files shift up to `keep`
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(RotatingFile {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            max_size,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            let _ = fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1));
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        *self = RotatingFile::open(self.path.clone(), self.max_size, self.keep)?;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", n));
+    path.into()
+}
+
+/// Date and time in UTC
+#[derive(Debug, PartialEq)]
+struct Utc {
+    year: u64,
+    month: u64,
+    day: u64,
+    hour: u64,
+    min: u64,
+    sec: u64,
+}
+
+impl From<SystemTime> for Utc {
+    fn from(time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (year, month, day) = civil_from_days(secs / 86400);
+        Utc {
+            year,
+            month,
+            day,
+            hour: secs % 86400 / 3600,
+            min: secs % 3600 / 60,
+            sec: secs % 60,
+        }
+    }
+}
+
+impl Utc {
+    fn clf(&self) -> String {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        format!(
+            "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.min,
+            self.sec
+        )
+    }
+
+    fn rfc3339(&self) -> String {
+        format!(
+            "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.min, self.sec
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry() -> Entry {
+        let mut req = http::Request::new(http::Method::Get, "http://localhost/hello?name=world");
+        req.set_peer_addr(Some("127.0.0.1:4000"));
+        req.insert_header(headers::USER_AGENT, "curl/7.0 \"test\"");
+        req.insert_header(crate::REQ_ID_HEADER, "42");
+        let log = AccessLog::new(Config::default(), &Opt::from_iter(&["valor"])).unwrap();
+        let mut entry = log.start(&mut req);
+        entry.time = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        entry.status = 200;
+        entry.plugin = Some("hello".into());
+        entry
+    }
+
+    #[test]
+    fn format_lines() {
+        let mut entry = entry();
+        assert_eq!(
+            Format::Common.line(&entry, 13),
+            "127.0.0.1 - - [09/Sep/2001:01:46:40 +0000] \"GET /hello?name=world HTTP/1.1\" 200 13"
+        );
+        assert_eq!(
+            Format::Combined.line(&entry, 0),
+            "127.0.0.1 - - [09/Sep/2001:01:46:40 +0000] \"GET /hello?name=world HTTP/1.1\" 200 - \"-\" \"curl/7.0 \\\"test\\\"\""
+        );
+        let json: serde_json::Value = serde_json::from_str(&Format::Json.line(&entry, 13)).unwrap();
+        assert_eq!(json["time"], "2001-09-09T01:46:40Z");
+        assert_eq!(json["id"], "42");
+        assert_eq!(json["plugin"], "hello");
+        assert_eq!(json["res_bytes"], 13);
+        assert_eq!(json["peer"], "127.0.0.1:4000");
+
+        entry.peer = Some("[::1]:4000".into());
+        assert!(Format::Common.line(&entry, 0).starts_with("::1 - - "));
+    }
+
+    #[test]
+    fn skip_plugins() {
+        let mut config = Config::default();
+        let log = AccessLog::new(Config::default(), &Opt::from_iter(&["valor"])).unwrap();
+        assert!(log.logs(Some("hello")) && log.logs(None));
+        assert!(!log.logs(Some("health")));
+
+        config.plugins = vec!["hello".into()];
+        let log = AccessLog::new(config, &Opt::from_iter(&["valor"])).unwrap();
+        assert!(log.logs(Some("hello")));
+        assert!(!log.logs(Some("other")) && !log.logs(None));
+
+        let log = AccessLog::new(
+            Config::default(),
+            &Opt::from_iter(&["valor", "--access-log", "off"]),
+        );
+        assert!(!log.unwrap().logs(Some("hello")));
+    }
+
+    #[test]
+    fn rotate_files() {
+        let dir = std::env::temp_dir().join(format!("valor-access-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("access.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+        let read = |path| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(rotated(&path, 1)), "third\n");
+        assert_eq!(read(rotated(&path, 2)), "second\n");
+        assert!(!rotated(&path, 3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
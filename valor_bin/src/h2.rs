@@ -0,0 +1,205 @@
+//! HTTP/2 connections, negotiated with ALPN over TLS or started with prior
+//! knowledge(h2c) by clients that send the connection preface right away.
+use async_std::{
+    future,
+    io::{self, BufRead, Read, ReadExt, Write},
+    net::TcpStream,
+    task,
+};
+use bytes::Bytes;
+use h2::{server::SendResponse, RecvStream, SendStream};
+use kv_log_macro::warn;
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use valor::http;
+
+/// Protocol name used with ALPN
+#[cfg(feature = "tls")]
+pub(crate) const ALPN: &[u8] = b"h2";
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Checks if the client starts the connection with the HTTP/2 preface without reading it
+pub(crate) async fn is_preface(stream: &TcpStream) -> bool {
+    let mut buf = [0; PREFACE.len()];
+    for _ in 0..10 {
+        let n = match stream.peek(&mut buf).await {
+            Ok(n) => n,
+            Err(_) => return false,
+        };
+        if n == 0 || buf[..n] != PREFACE[..n] {
+            return false;
+        }
+        if n == PREFACE.len() {
+            return true;
+        }
+        // the preface was split, wait a bit for the rest
+        task::sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+pub(crate) async fn accept<S>(stream: S, conn: crate::Conn) -> Result<(), valor::Error>
+where
+    S: Read + Write + Unpin + 'static,
+{
+    let mut connection = h2::server::handshake(stream.compat())
+        .await
+        .map_err(internal_error)?;
+    // requests make progress as long as the connection is polled
+    while let Some(accepted) = connection.accept().await {
+        let (req, respond) = accepted.map_err(internal_error)?;
+        let conn = conn.clone();
+        task::spawn_local(async move {
+            if let Err(err) = handle(req, respond, &conn).await {
+                warn!("{}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle(
+    req: ::http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    conn: &crate::Conn,
+) -> Result<(), valor::Error> {
+    let req = into_request(req)?;
+    let mut res = crate::handle(conn, req).await;
+
+    let mut head = ::http::Response::builder().status(u16::from(res.status()));
+    for (name, values) in res.iter() {
+        // connection specific headers are not allowed in HTTP/2
+        if CONNECTION_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        for value in values {
+            head = head.header(name.as_str(), value.as_str());
+        }
+    }
+    let head = head.body(()).map_err(internal_error)?;
+    let mut body = res.take_body();
+    let empty = body.is_empty() == Some(true);
+    let mut send = respond.send_response(head, empty).map_err(internal_error)?;
+    if empty {
+        return Ok(());
+    }
+    // the body is sent as it's read so streamed responses reach the client right away
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let n = body.read(&mut chunk).await.map_err(internal_error)?;
+        if n == 0 {
+            break;
+        }
+        send_data(&mut send, Bytes::copy_from_slice(&chunk[..n])).await?;
+    }
+    send.send_data(Bytes::new(), true).map_err(internal_error)?;
+    Ok(())
+}
+
+/// Waits for the client to have room for the data so slow readers don't pile it up in memory
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<(), valor::Error> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = match future::poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(capacity) => capacity.map_err(internal_error)?,
+            None => return Err(internal_error("Stream closed")),
+        };
+        let ready = data.split_to(capacity.min(data.len()));
+        if !ready.is_empty() {
+            send.send_data(ready, false).map_err(internal_error)?;
+        }
+    }
+    Ok(())
+}
+
+const CONNECTION_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn into_request(req: ::http::Request<RecvStream>) -> Result<http::Request, valor::Error> {
+    let (parts, body) = req.into_parts();
+    let method = parts.method.as_str().parse().map_err(bad_request)?;
+    let url = match parts.uri.authority() {
+        Some(_) => parts.uri.to_string(),
+        None => format!("http://localhost{}", parts.uri),
+    };
+    let mut req = http::Request::new(method, url.as_str());
+    req.set_version(Some(http::Version::Http2_0));
+    for (name, value) in &parts.headers {
+        let value = value.to_str().map_err(bad_request)?;
+        req.append_header(name.as_str(), value)
+            .map_err(bad_request)?;
+    }
+
+    let len = parts
+        .headers
+        .get(::http::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse().ok());
+    let body = Incoming {
+        body,
+        chunk: Bytes::new(),
+    };
+    req.set_body(http::Body::from_reader(body, len));
+    Ok(req)
+}
+
+/// Body of a request read from its stream as the plugin consumes it, the client
+/// gets to send more only once the data it sent before is read
+struct Incoming {
+    body: RecvStream,
+    chunk: Bytes,
+}
+
+impl Read for Incoming {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let chunk = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl BufRead for Incoming {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        while this.chunk.is_empty() {
+            match ready!(this.body.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = this.body.flow_control().release_capacity(data.len());
+                    this.chunk = data;
+                }
+                Some(Err(err)) => return Poll::Ready(Err(io::Error::other(err))),
+                None => break,
+            }
+        }
+        Poll::Ready(Ok(&this.chunk))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        let _ = self.chunk.split_to(amt);
+    }
+}
+
+fn bad_request(err: impl std::fmt::Display) -> valor::Error {
+    http::Error::from_str(http::StatusCode::BadRequest, err.to_string()).into()
+}
+
+fn internal_error(err: impl std::fmt::Display) -> valor::Error {
+    http::Error::from_str(http::StatusCode::InternalServerError, err.to_string()).into()
+}
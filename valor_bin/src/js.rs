@@ -0,0 +1,433 @@
+//! JavaScript vlugins run with an embedded engine. Like in the browser a
+//! plugin is an ES module that exports a `handler` function receiving a
+//! fetch-like `Request` and returning a `Response` or a promise of one.
+//! The `valor` global lets it talk to other plugins through the runtime.
+use async_std::task;
+use async_trait::async_trait;
+use boa_engine::{
+    builtins::promise::PromiseState,
+    js_string,
+    module::SimpleModuleLoader,
+    object::{builtins::JsPromise, JsObject},
+    Context as JsContext, JsNativeError, JsValue, Module, NativeFunction, Source,
+};
+use kv_log_macro::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, collections::BTreeMap, convert::TryFrom, path::Path, rc::Rc};
+use valor::{http, runtime, Answer, Context, Message, Messenger, Params, Vlugin};
+
+const PRELUDE: &str = include_str!("js/prelude.js");
+/// Iterations a single loop can run before the engine throws an error that
+/// scripts can't catch, so a module stuck in a loop doesn't block the server
+const LOOP_ITERATION_LIMIT: u64 = 1_000_000;
+
+/// Only modules available locally are supported, e.g. `file:///path/plugin.js`
+pub(crate) fn is_local_module(url: &str) -> bool {
+    (url.ends_with(".js") || url.ends_with(".mjs"))
+        && (url.starts_with("file://") || !url.contains("://"))
+}
+
+pub(crate) fn load<'a>(
+    plugin: &runtime::VluginDef,
+) -> Result<runtime::VluginFactory<'a>, runtime::Error> {
+    let name = plugin.name.clone();
+    let path = match &plugin.r#type {
+        runtime::VluginType::Web { url } => url.trim_start_matches("file://"),
+        ty => return Err(runtime::Error::VluginNotSupported(ty.to_owned())),
+    };
+    debug!("loading js plugin {}({})", name, path);
+    let path = Path::new(path).canonicalize().map_err(|e| {
+        warn!("{}", e);
+        runtime::Error::LoadVlugin(name.clone())
+    })?;
+
+    Ok(Box::new(move |_cfg| {
+        let vlugin = JsVlugin::instantiate(&path);
+        Box::pin(async move { Ok(Box::new(vlugin?) as Box<dyn Vlugin>) })
+    }))
+}
+
+/// Request as it's handed to the module
+#[derive(Serialize, Deserialize)]
+struct JsRequest {
+    url: String,
+    method: String,
+    headers: Vec<(String, String)>,
+    body: String,
+    #[serde(default)]
+    params: BTreeMap<String, String>,
+}
+
+impl JsRequest {
+    async fn from_http(mut req: http::Request) -> Result<Self, valor::Error> {
+        Ok(JsRequest {
+            url: req.url().to_string(),
+            method: req.method().to_string(),
+            headers: req
+                .iter()
+                .flat_map(|(name, values)| values.iter().map(move |v| (name, v)))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            params: req
+                .ext()
+                .get::<Params>()
+                .into_iter()
+                .flat_map(Params::iter)
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+            body: req.body_string().await?,
+        })
+    }
+
+    fn into_http(self) -> Result<http::Request, valor::Error> {
+        fn bad_request<E>(_: E) -> http::Error {
+            http::Error::from_str(http::StatusCode::BadRequest, "Invalid request")
+        }
+        let method = self.method.parse::<http::Method>().map_err(bad_request)?;
+        let url = http::Url::parse(&self.url).map_err(bad_request)?;
+        let mut req = http::Request::new(method, url);
+        for (name, value) in self.headers {
+            req.append_header(name.as_str(), value.as_str())
+                .map_err(bad_request)?;
+        }
+        if !self.params.is_empty() {
+            req.ext_mut()
+                .insert(self.params.into_iter().collect::<Params>());
+        }
+        req.set_body(self.body);
+        Ok(req)
+    }
+}
+
+/// Response as the module hands it back
+#[derive(Serialize, Deserialize)]
+struct JsResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl JsResponse {
+    async fn from_http(mut res: http::Response) -> Result<Self, valor::Error> {
+        Ok(JsResponse {
+            status: res.status().into(),
+            headers: res
+                .iter()
+                .flat_map(|(name, values)| values.iter().map(move |v| (name, v)))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: res.body_string().await?,
+        })
+    }
+
+    fn into_http(self) -> Result<http::Response, valor::Error> {
+        let status = http::StatusCode::try_from(self.status).map_err(internal_error)?;
+        let mut res = http::Response::new(status);
+        for (name, value) in self.headers {
+            res.append_header(name.as_str(), value.as_str())
+                .map_err(internal_error)?;
+        }
+        res.set_body(self.body);
+        Ok(res)
+    }
+}
+
+/// What the module asks the runtime with the functions of the `valor` global
+#[derive(Deserialize)]
+#[serde(tag = "call", rename_all = "camelCase")]
+enum JsCall {
+    Send {
+        to: String,
+        request: JsRequest,
+    },
+    Publish {
+        topic: String,
+        #[serde(default)]
+        payload: serde_json::Value,
+    },
+    SendFrame {
+        id: String,
+        frame: String,
+    },
+    CloseSocket {
+        id: String,
+    },
+}
+
+impl JsCall {
+    /// Answers with the response of a plugin or `null`
+    async fn answer(self, cx: &Context) -> Result<serde_json::Value, valor::Error> {
+        match self {
+            JsCall::Send { to, request } => {
+                if let Answer::Http(res) = cx.send(&to, request.into_http()?).await? {
+                    let res = JsResponse::from_http(res).await?;
+                    return Ok(serde_json::to_value(res).expect("valid json"));
+                }
+            }
+            JsCall::Publish { topic, payload } => cx.publish(&topic, payload).await?,
+            JsCall::SendFrame { id, frame } => cx.send_frame(&id, frame)?,
+            JsCall::CloseSocket { id } => cx.close_socket(&id)?,
+        }
+        Ok(serde_json::Value::Null)
+    }
+}
+
+type Linked = Rc<RefCell<Option<Rc<dyn Messenger>>>>;
+
+/// Function behind the `valor` global, calls are answered before the module continues
+fn host_call(linked: Linked) -> NativeFunction {
+    let call = move |_: &JsValue, args: &[JsValue], js: &mut JsContext| {
+        let error = |err: String| JsNativeError::error().with_message(err);
+        let call = args.first().cloned().unwrap_or_default().to_json(js)?;
+        let call: JsCall = serde_json::from_value(call).map_err(|e| error(e.to_string()))?;
+        let mut cx = Context::default();
+        if let Some(messenger) = linked.borrow().clone() {
+            cx.with_messenger(messenger);
+        }
+        let answer = task::block_on(call.answer(&cx)).map_err(|e| error(e.to_string()))?;
+        JsValue::from_json(&answer, js)
+    };
+    // the closure doesn't hold anything the garbage collector has to trace
+    unsafe { NativeFunction::from_closure(call) }
+}
+
+struct JsVlugin {
+    js: RefCell<JsContext>,
+    handle: JsObject,
+    handler: JsObject,
+    linked: Linked,
+    cx: Context,
+}
+
+impl JsVlugin {
+    fn instantiate(path: &Path) -> Result<Self, valor::Error> {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let loader = Rc::new(SimpleModuleLoader::new(dir).map_err(internal_error)?);
+        let mut js = JsContext::builder()
+            .module_loader(loader.clone())
+            .build()
+            .map_err(internal_error)?;
+        js.runtime_limits_mut()
+            .set_loop_iteration_limit(LOOP_ITERATION_LIMIT);
+        let linked = Linked::default();
+        js.register_global_builtin_callable(
+            js_string!("__valor_call"),
+            1,
+            host_call(linked.clone()),
+        )
+        .map_err(internal_error)?;
+
+        js.eval(Source::from_bytes(PRELUDE))
+            .map_err(internal_error)?;
+        let handle = js
+            .global_object()
+            .get(js_string!("__valor_handle"), &mut js)
+            .map_err(internal_error)?
+            .as_callable()
+            .cloned()
+            .ok_or_else(|| internal_error("Missing prelude"))?;
+
+        let src = Source::from_filepath(path).map_err(internal_error)?;
+        let module = Module::parse(src, None, &mut js).map_err(internal_error)?;
+        loader.insert(path.into(), module.clone());
+        let loaded = module.load_link_evaluate(&mut js);
+        js.run_jobs();
+        if let PromiseState::Rejected(err) = loaded.state() {
+            return Err(internal_error(err.display()));
+        }
+        let handler = module
+            .namespace(&mut js)
+            .get(js_string!("handler"), &mut js)
+            .map_err(internal_error)?
+            .as_callable()
+            .cloned()
+            .ok_or_else(|| internal_error("Module doesn't export a handler"))?;
+
+        Ok(JsVlugin {
+            js: RefCell::new(js),
+            handle,
+            handler,
+            linked,
+            cx: Context::default(),
+        })
+    }
+
+    fn handle(&self, req: JsRequest) -> Result<JsResponse, valor::Error> {
+        // a module messaging itself would have to wait for its own answer
+        let mut js = self
+            .js
+            .try_borrow_mut()
+            .map_err(|_| internal_error("Plugin can't answer while it's waiting on a call"))?;
+        let js = &mut *js;
+        let req = serde_json::to_value(req).expect("valid json");
+        let req = JsValue::from_json(&req, js).map_err(internal_error)?;
+        let res = self
+            .handle
+            .call(
+                &JsValue::undefined(),
+                &[self.handler.clone().into(), req],
+                js,
+            )
+            .map_err(internal_error)?;
+        let res = res
+            .as_object()
+            .and_then(|res| JsPromise::from_object(res.clone()).ok())
+            .ok_or_else(|| internal_error("Expected a promise"))?;
+        // promises can only wait on other promises, there's no I/O to wait for
+        js.run_jobs();
+        match res.state() {
+            PromiseState::Fulfilled(res) => {
+                let res = res.to_json(js).map_err(internal_error)?;
+                serde_json::from_value(res).map_err(internal_error)
+            }
+            PromiseState::Rejected(err) => Err(internal_error(err.display())),
+            PromiseState::Pending => Err(internal_error("Handler never answered")),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Vlugin for JsVlugin {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, valor::Error> {
+        let req = match msg {
+            Message::Http(req) => req,
+            Message::Ping | Message::Health => return Ok(Answer::Pong),
+            _ => return Err(valor::Error::NotSupported),
+        };
+        let req = JsRequest::from_http(req).await?;
+        Ok(self.handle(req)?.into_http()?.into())
+    }
+
+    fn link(&mut self, messenger: Rc<dyn Messenger>) {
+        self.linked.replace(Some(messenger));
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        &mut self.cx
+    }
+    fn context(&self) -> &Context {
+        &self.cx
+    }
+}
+
+fn internal_error(err: impl std::fmt::Display) -> valor::Error {
+    warn!("{}", err);
+    http::Error::from_str(http::StatusCode::InternalServerError, "Plugin crashed").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn module(src: &str) -> Result<JsVlugin, valor::Error> {
+        let path = std::env::temp_dir().join(format!("{}.mjs", uuid::Uuid::new_v4()));
+        fs::write(&path, src).expect("module written");
+        let vlugin = JsVlugin::instantiate(&path);
+        let _ = fs::remove_file(&path);
+        vlugin
+    }
+
+    #[async_std::test]
+    async fn module_handles_requests() -> Result<(), valor::Error> {
+        let vlugin = module(
+            r#"
+            export async function handler(req) {
+              const { from } = await req.json();
+              const { who } = req.params;
+              const { pathname, searchParams } = new URL(req.url);
+              const greeting = `${from} says ${searchParams.get("say")} ${who} from ${pathname}`;
+              return Response.json({ greeting, method: req.method }, {
+                status: 201,
+                headers: { "x-plugin": req.headers.get("X-Plugin") },
+              });
+            }
+            "#,
+        );
+
+        let mut req = http::Request::new(http::Method::Post, "http://valor/js?say=hi+there");
+        req.insert_header("x-plugin", "js");
+        req.ext_mut()
+            .insert(vec![("who", "Bob")].into_iter().collect::<Params>());
+        req.set_body(r#"{"from":"Alice"}"#);
+        let mut res: http::Response = vlugin?.on_msg(req.into()).await?.into();
+        assert_eq!(res.status(), http::StatusCode::Created);
+        assert_eq!(res.header("x-plugin").unwrap(), "js");
+        assert_eq!(res.content_type(), Some(http::mime::JSON));
+        assert_eq!(
+            res.body_string().await?,
+            r#"{"greeting":"Alice says hi there Bob from /js","method":"POST"}"#
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn handlers_that_loop_forever_are_stopped() -> Result<(), valor::Error> {
+        let vlugin = module(
+            r#"
+            export function handler(req) {
+              try { while (true) {} } catch (_) {}
+              return new Response("unreachable");
+            }
+            "#,
+        )?;
+        let req = http::Request::new(http::Method::Get, "http://valor/js");
+        assert!(vlugin.on_msg(req.into()).await.is_err());
+        Ok(())
+    }
+
+    type Calls = Rc<RefCell<Vec<String>>>;
+
+    struct Recorder(Calls);
+
+    #[async_trait(?Send)]
+    impl Messenger for Recorder {
+        async fn send(&self, to: &str, msg: Message) -> Result<Answer, valor::Error> {
+            let req = http::Request::try_from(msg).map_err(|_| valor::Error::NotSupported)?;
+            Ok(http::Response::from(format!("{} got {}", to, req.url().path())).into())
+        }
+        async fn publish(&self, topic: &str, payload: serde_json::Value) {
+            self.0
+                .borrow_mut()
+                .push(format!("publish {} {}", topic, payload));
+        }
+        fn socket(&self, id: &str) -> Option<Rc<dyn valor::Socket>> {
+            (id == "1").then(|| Rc::new(Recorder(self.0.clone())) as Rc<dyn valor::Socket>)
+        }
+    }
+
+    impl valor::Socket for Recorder {
+        fn send(&self, frame: valor::Frame) -> Result<(), valor::Error> {
+            self.0.borrow_mut().push(format!("frame {:?}", frame));
+            Ok(())
+        }
+        fn close(&self) {}
+    }
+
+    #[async_std::test]
+    async fn modules_talk_to_the_runtime() -> Result<(), valor::Error> {
+        let mut vlugin = module(
+            r#"
+            export async function handler(req) {
+              const res = await valor.send("other", new Request("http://valor/hi"));
+              await valor.publish("visits", { from: "js" });
+              valor.sendFrame("1", "hey");
+              let error;
+              try { valor.sendFrame("2", "hey"); } catch (err) { error = err.message; }
+              return new Response(`${await res.text()}, ${error}`);
+            }
+            "#,
+        )?;
+        let calls = Calls::default();
+        vlugin.link(Rc::new(Recorder(calls.clone())));
+
+        let req = http::Request::new(http::Method::Get, "http://valor/js");
+        let mut res: http::Response = vlugin.on_msg(req.into()).await?.into();
+        assert_eq!(res.body_string().await?, "other got /hi, Socket not found");
+        assert_eq!(
+            *calls.borrow(),
+            [r#"publish visits {"from":"js"}"#, r#"frame Text("hey")"#]
+        );
+        Ok(())
+    }
+}
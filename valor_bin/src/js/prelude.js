@@ -0,0 +1,133 @@
+// Minimal version of the fetch API classes plugins get in the browser,
+// bodies are kept as text.
+class Headers {
+  #map = new Map();
+
+  constructor(init = []) {
+    const entries = init instanceof Headers ? init.entries()
+      : Array.isArray(init) ? init : Object.entries(init);
+    for (const [name, value] of entries) this.append(name, value);
+  }
+
+  append(name, value) {
+    name = String(name).toLowerCase();
+    const prev = this.#map.get(name);
+    this.#map.set(name, prev === undefined ? String(value) : `${prev}, ${value}`);
+  }
+  set(name, value) { this.#map.set(String(name).toLowerCase(), String(value)); }
+  get(name) { return this.#map.get(String(name).toLowerCase()) ?? null; }
+  has(name) { return this.#map.has(String(name).toLowerCase()); }
+  delete(name) { this.#map.delete(String(name).toLowerCase()); }
+  forEach(fn, self) { this.#map.forEach((v, k) => fn.call(self, v, k, this)); }
+  entries() { return this.#map.entries(); }
+  keys() { return this.#map.keys(); }
+  values() { return this.#map.values(); }
+  [Symbol.iterator]() { return this.entries(); }
+}
+
+class URLSearchParams {
+  #list = [];
+
+  constructor(init = "") {
+    const entries = typeof init === "string"
+      ? init.replace(/^\?/, "").split("&").filter(Boolean).map((pair) => {
+        const [name, ...value] = pair.split("=");
+        return [decode(name), decode(value.join("="))];
+      })
+      : Array.isArray(init) ? init : Object.entries(init);
+    for (const [name, value] of entries) this.append(name, value);
+  }
+
+  append(name, value) { this.#list.push([String(name), String(value)]); }
+  get(name) { return this.#list.find(([n]) => n === name)?.[1] ?? null; }
+  getAll(name) { return this.#list.filter(([n]) => n === name).map(([, v]) => v); }
+  has(name) { return this.#list.some(([n]) => n === name); }
+  entries() { return this.#list[Symbol.iterator](); }
+  [Symbol.iterator]() { return this.entries(); }
+  toString() {
+    return this.#list.map(([n, v]) => `${encodeURIComponent(n)}=${encodeURIComponent(v)}`).join("&");
+  }
+}
+
+const decode = (s) => decodeURIComponent(s.replace(/\+/g, " "));
+
+class URL {
+  constructor(url) {
+    const match = /^([a-z][a-z0-9+.-]*:)\/\/([^/?#]*)([^?#]*)(\?[^#]*)?(#.*)?$/i.exec(String(url));
+    if (!match) throw new TypeError(`Invalid URL: ${url}`);
+    const [, protocol, host, pathname, search = "", hash = ""] = match;
+    Object.assign(this, { protocol, host, pathname: pathname || "/", search, hash });
+    this.hostname = host.replace(/:\d+$/, "");
+    this.port = /:(\d+)$/.exec(host)?.[1] ?? "";
+    this.origin = `${protocol}//${host}`;
+    this.searchParams = new URLSearchParams(search);
+  }
+
+  get href() { return `${this.origin}${this.pathname}${this.search}${this.hash}`; }
+  toString() { return this.href; }
+}
+
+class Body {
+  #body;
+  bodyUsed = false;
+
+  constructor(body) { this.#body = body == null ? "" : String(body); }
+
+  async text() {
+    if (this.bodyUsed) throw new TypeError("Body already used");
+    this.bodyUsed = true;
+    return this.#body;
+  }
+  async json() { return JSON.parse(await this.text()); }
+}
+
+class Request extends Body {
+  constructor(url, init = {}) {
+    super(init.body);
+    this.url = String(url);
+    this.method = (init.method ?? "GET").toUpperCase();
+    this.headers = new Headers(init.headers);
+    // params captured by the plugin's route pattern
+    this.params = init.params ?? {};
+  }
+}
+
+class Response extends Body {
+  constructor(body, init = {}) {
+    super(body);
+    this.status = init.status ?? 200;
+    this.statusText = init.statusText ?? "";
+    this.headers = new Headers(init.headers);
+  }
+
+  get ok() { return this.status >= 200 && this.status < 300; }
+
+  static json(data, init = {}) {
+    const res = new Response(JSON.stringify(data), init);
+    if (!res.headers.has("content-type")) res.headers.set("content-type", "application/json");
+    return res;
+  }
+}
+
+Object.assign(globalThis, { URL, URLSearchParams, Headers, Request, Response });
+
+// Called by the runtime with the module's handler and a plain request
+globalThis.__valor_handle = async (handler, { url, method, headers, body, params }) => {
+  let res = await handler(new Request(url, { method, headers, body, params }));
+  if (!(res instanceof Response)) res = new Response(res);
+  return { status: res.status, headers: [...res.headers], body: await res.text() };
+};
+
+// Talks to other plugins and WebSocket clients through the runtime
+globalThis.valor = {
+  async send(to, request) {
+    if (!(request instanceof Request)) request = new Request(request);
+    const { url, method, headers, params } = request;
+    const body = await request.text();
+    const res = __valor_call({ call: "send", to, request: { url, method, headers: [...headers], body, params } });
+    return res && new Response(res.body, { status: res.status, headers: res.headers });
+  },
+  async publish(topic, payload = null) { __valor_call({ call: "publish", topic, payload }); },
+  sendFrame(id, frame) { __valor_call({ call: "sendFrame", id, frame: String(frame) }); },
+  closeSocket(id) { __valor_call({ call: "closeSocket", id }); },
+};
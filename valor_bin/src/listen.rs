@@ -0,0 +1,195 @@
+//! Sockets the server accepts connections from, TCP addresses, Unix domain
+//! sockets and the ones passed by a service manager like systemd.
+use async_std::net::TcpListener;
+#[cfg(unix)]
+use async_std::os::unix::net::UnixListener;
+#[cfg(feature = "tls")]
+pub(crate) use futures_rustls::TlsAcceptor;
+#[cfg(unix)]
+use std::{env, fs, os::unix::prelude::*, path::PathBuf};
+use std::{fmt, io, str::FromStr};
+
+pub(crate) const DEFAULT_ADDR: &str = "0.0.0.0:8080";
+
+/// Stand-in so the same signatures work when the server is built without TLS support
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub(crate) enum TlsAcceptor {}
+
+/// Address given in the command line, e.g. `127.0.0.1:8080`, `tls:0.0.0.0:8443`
+/// or `unix:/run/valor.sock`
+#[derive(Debug)]
+pub(crate) enum ListenAddr {
+    Tcp(String),
+    #[cfg(feature = "tls")]
+    Tls(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return Ok(ListenAddr::Unix(path.into()));
+        }
+        #[cfg(feature = "tls")]
+        if let Some(addr) = addr.strip_prefix("tls:") {
+            return match addr.parse()? {
+                ListenAddr::Tcp(addr) => Ok(ListenAddr::Tls(addr)),
+                _ => Err(format!("{} should be a `host:port`", addr)),
+            };
+        }
+        match addr.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => Ok(ListenAddr::Tcp(addr.into())),
+            _ => Err(format!("{} should be a `host:port` or `unix:path`", addr)),
+        }
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, TlsAcceptor),
+    /// Sockets the server created have their path to clean up after them
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// TLS addresses need an acceptor with the certificates to present
+    pub async fn bind(addr: &ListenAddr, tls: Option<&TlsAcceptor>) -> io::Result<Self> {
+        #[cfg(not(feature = "tls"))]
+        let _ = tls;
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(feature = "tls")]
+            ListenAddr::Tls(addr) => {
+                let tls = tls.cloned().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no TLS certificate")
+                })?;
+                Ok(Listener::Tls(TcpListener::bind(addr).await?, tls))
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // a socket left behind by a previous run would make binding fail
+                if fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path).await?;
+                Ok(Listener::Unix(listener, Some(path.clone())))
+            }
+        }
+    }
+}
+
+/// Sockets passed with systemd's socket activation protocol, see `sd_listen_fds(3)`
+#[derive(Default)]
+pub(crate) struct Activated(#[cfg(unix)] Vec<RawFd>);
+
+impl Activated {
+    /// Takes the sockets meant for this process and clears the variables describing them,
+    /// the environment can't be safely changed once other threads are running
+    #[cfg(unix)]
+    pub fn from_env() -> Self {
+        const LISTEN_FDS_START: RawFd = 3;
+        let for_us =
+            env::var("LISTEN_PID").map_or(true, |pid| pid == std::process::id().to_string());
+        let fds = env::var("LISTEN_FDS")
+            .ok()
+            .filter(|_| for_us)
+            .and_then(|n| n.parse::<RawFd>().ok())
+            .unwrap_or(0);
+        // they are not meant for child processes
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        Activated((LISTEN_FDS_START..LISTEN_FDS_START + fds).collect())
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_env() -> Self {
+        Activated()
+    }
+
+    #[cfg(unix)]
+    pub fn into_listeners(self) -> Vec<Listener> {
+        self.0
+            .into_iter()
+            .map(|fd| {
+                let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                // the address of a socket of another family isn't a valid Unix address
+                match unix.local_addr() {
+                    Ok(_) => Listener::Unix(unix.into(), None),
+                    Err(_) => {
+                        let fd = unix.into_raw_fd();
+                        Listener::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) }.into())
+                    }
+                }
+            })
+            .collect()
+    }
+
+    #[cfg(not(unix))]
+    pub fn into_listeners(self) -> Vec<Listener> {
+        Vec::new()
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "http://{}", addr),
+                Err(_) => write!(f, "tcp socket"),
+            },
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, _) => match listener.local_addr() {
+                Ok(addr) => write!(f, "https://{}", addr),
+                Err(_) => write!(f, "tls socket"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => write!(f, "unnamed unix socket"),
+                },
+                Err(_) => write!(f, "unix socket"),
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_addresses() {
+        assert!(
+            matches!("127.0.0.1:8080".parse(), Ok(ListenAddr::Tcp(a)) if a == "127.0.0.1:8080")
+        );
+        assert!(matches!("[::1]:80".parse(), Ok(ListenAddr::Tcp(_))));
+        #[cfg(unix)]
+        assert!(
+            matches!("unix:/run/valor.sock".parse(), Ok(ListenAddr::Unix(p)) if p.as_os_str() == "/run/valor.sock")
+        );
+        assert!("localhost".parse::<ListenAddr>().is_err());
+        assert!("localhost:http".parse::<ListenAddr>().is_err());
+        #[cfg(feature = "tls")]
+        assert!(
+            matches!("tls:0.0.0.0:8443".parse(), Ok(ListenAddr::Tls(a)) if a == "0.0.0.0:8443")
+        );
+    }
+}
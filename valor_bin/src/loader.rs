@@ -1,12 +1,47 @@
+use async_std::io::{self, BufRead, Read};
 use async_trait::async_trait;
-use kv_log_macro::{debug, warn};
-use libloading::{library_filename, Library, Symbol};
-use std::{cell::RefCell, collections::HashMap, pin::Pin, rc::Rc};
-use valor::{runtime, Vlugin, VluginConfig};
+use kv_log_macro::{debug, info, warn};
+use libloading::{library_filename, Library};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::SystemTime,
+};
+use uuid::Uuid;
+use valor::{
+    abi::ffi::{ForeignVlugin, Metadata, VluginVTable, ABI_VERSION, CORE_VERSION},
+    http, runtime, Answer, Context, Message, Messenger, Vlugin,
+};
 
-#[derive(Default)]
 pub(crate) struct Loader {
-    plugins: RefCell<HashMap<String, Rc<Library>>>,
+    plugins: RefCell<HashMap<String, NativeLib>>,
+    #[cfg(feature = "wasm")]
+    engine: wasmtime::Engine,
+}
+
+impl Default for Loader {
+    fn default() -> Self {
+        Loader {
+            plugins: RefCell::default(),
+            #[cfg(feature = "wasm")]
+            engine: crate::wasm::engine(),
+        }
+    }
+}
+
+/// A loaded library and the file it came from
+struct NativeLib {
+    lib: Arc<Library>,
+    source: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    /// Version of the file that couldn't be reloaded, it's retried once the file changes again
+    failed: Option<SystemTime>,
 }
 
 #[async_trait(?Send)]
@@ -18,53 +53,330 @@ impl runtime::Loader for Loader {
         match &plugin.r#type {
             runtime::VluginType::Native { path } => {
                 let name = &plugin.name;
-                if let Some(factory) = self.get_factory(name) {
-                    return Ok(factory);
+                let changed = self.changed(name);
+                if !changed {
+                    if let Some(factory) = self.get_factory(name) {
+                        return Ok(factory);
+                    }
                 }
 
                 let path = path
                     .as_ref()
                     .map(Into::into)
                     .unwrap_or_else(|| library_filename(name));
-                debug!("loading native plugin {}({})", name, path.to_string_lossy());
-                let lib = unsafe { Library::new(path) }.map_err(|e| {
+                let source = find_library(Path::new(&path));
+                let modified = source.as_deref().and_then(modified_time);
+                let lib = match source.as_deref() {
+                    Some(source) if changed => {
+                        info!("reloading native plugin {}({})", name, source.display());
+                        open_fresh_copy(name, source)
+                    }
+                    _ => {
+                        debug!("loading native plugin {}({})", name, path.to_string_lossy());
+                        unsafe { Library::new(path) }.map_err(|e| e.into())
+                    }
+                }
+                .map_err(|e| {
                     warn!("{}", e);
                     runtime::Error::LoadVlugin(name.to_owned())
+                })
+                .and_then(|lib| check_abi(name, &lib).map(|_| lib))
+                .inspect_err(|_| {
+                    if let Some(lib) = self.plugins.borrow_mut().get_mut(name) {
+                        lib.failed = modified;
+                    }
                 })?;
 
                 {
-                    self.plugins.borrow_mut().insert(name.into(), Rc::new(lib));
+                    let lib = NativeLib {
+                        lib: Arc::new(lib),
+                        source,
+                        modified,
+                        failed: None,
+                    };
+                    self.plugins.borrow_mut().insert(name.into(), lib);
                 }
 
                 self.get_factory(name)
                     .ok_or(runtime::Error::LoadVlugin(name.to_owned()))
             }
+            #[cfg(feature = "wasm")]
+            runtime::VluginType::Web { url } if crate::wasm::is_local_module(url) => {
+                crate::wasm::load(&self.engine, plugin)
+            }
+            #[cfg(feature = "js")]
+            runtime::VluginType::Web { url } if crate::js::is_local_module(url) => {
+                crate::js::load(plugin)
+            }
+            #[cfg(feature = "wasi")]
+            runtime::VluginType::Wasi { .. } => crate::wasm::load(&self.engine, plugin),
             ty => Err(runtime::Error::VluginNotSupported(ty.to_owned())),
         }
     }
 }
 
-type Factory<'a> = fn(
-    Option<VluginConfig>,
-) -> Pin<
-    Box<dyn core::future::Future<Output = Result<Box<dyn Vlugin>, valor::Error>> + 'a>,
->;
+type VTableFn = extern "C" fn() -> VluginVTable;
+type MetadataFn = extern "C" fn() -> Metadata;
 
 impl Loader {
+    /// Names of the loaded plugins whose library changed on disk since it was loaded,
+    /// a version that failed to reload isn't reported again
+    pub fn changed_plugins(&self) -> Vec<String> {
+        self.plugins
+            .borrow()
+            .keys()
+            .filter(|name| self.changed(name))
+            .cloned()
+            .collect()
+    }
+
+    fn changed(&self, name: &str) -> bool {
+        let plugins = self.plugins.borrow();
+        let lib = match plugins.get(name) {
+            Some(lib) => lib,
+            None => return false,
+        };
+        let modified = lib.source.as_deref().and_then(modified_time);
+        modified.is_some() && modified != lib.modified && modified != lib.failed
+    }
+
     fn get_factory(&self, name: &str) -> Option<runtime::VluginFactory> {
-        let lib = self.plugins.borrow().get(name)?.clone();
-        let name = name.to_owned();
+        let lib = self.plugins.borrow().get(name)?.lib.clone();
+        let vtable = unsafe { lib.get::<VTableFn>(b"valor_vlugin") }.ok()?();
 
         Some(Box::new(move |cfg| {
             let lib = lib.clone();
-            let name = name.clone();
             Box::pin(async move {
-                let lib = lib.clone();
-                let factory: Symbol<'_, Factory> =
-                    unsafe { lib.get(b"instantiate_vlugin") }.expect("Plugin interface");
-                debug!("{} {:?}", name, factory);
-                factory(cfg).await
+                let vlugin = unsafe { ForeignVlugin::create(vtable, cfg) }.await?;
+                let vlugin = Box::new(vlugin);
+                Ok(Box::new(NativeVlugin { vlugin, lib }) as Box<dyn Vlugin>)
             })
         }))
     }
 }
+
+/// Plugins built against a different version of the interface or
+/// an incompatible `valor_core` can't be used
+fn check_abi(name: &str, lib: &Library) -> Result<(), runtime::Error> {
+    let incompatible = |reason: String| {
+        warn!("{} is incompatible: {}", name, reason);
+        runtime::Error::IncompatibleVlugin(name.into(), reason)
+    };
+    let version = unsafe { lib.get::<*const u32>(b"VALOR_ABI_VERSION") }
+        .map(|v| unsafe { **v })
+        .map_err(|_| incompatible("doesn't export a plugin interface".into()))?;
+    if version != ABI_VERSION {
+        return Err(incompatible(format!(
+            "uses plugin interface v{} but v{} is required",
+            version, ABI_VERSION
+        )));
+    }
+
+    // the layout of the metadata is known once the interface version matches
+    let meta = unsafe { lib.get::<MetadataFn>(b"valor_metadata") }
+        .map(|meta| meta())
+        .map_err(|_| incompatible("doesn't export its metadata".into()))?;
+    if !meta.is_compatible() {
+        return Err(incompatible(format!(
+            "built with valor {} but the runtime uses {}",
+            meta.core_version.as_str(),
+            CORE_VERSION
+        )));
+    }
+    debug!(
+        "{} is {} v{}, built with valor {} and {}",
+        name,
+        meta.name.as_str(),
+        meta.version.as_str(),
+        meta.core_version.as_str(),
+        meta.rustc_version.as_str(),
+        {
+            routes: meta.routes().collect::<Vec<_>>().join(", "),
+        }
+    );
+    Ok(())
+}
+
+/// Keeps the library a vlugin was created from loaded for as long as the vlugin lives
+struct NativeVlugin {
+    // NOTE fields are dropped in order, the vlugin has to go before its library
+    vlugin: Box<ForeignVlugin>,
+    lib: Arc<Library>,
+}
+
+#[async_trait(?Send)]
+impl Vlugin for NativeVlugin {
+    async fn on_create(&mut self) -> Result<(), valor::Error> {
+        self.vlugin.on_create().await
+    }
+
+    async fn on_destroy(&self) -> Result<(), valor::Error> {
+        self.vlugin.on_destroy().await
+    }
+
+    async fn on_msg(&self, msg: Message) -> Result<Answer, valor::Error> {
+        match self.vlugin.on_msg(msg).await? {
+            // streamed bodies are read from the library after the vlugin answers
+            Answer::Http(mut res) if res.len().is_none() => {
+                let body = LoadedBody {
+                    body: res.take_body(),
+                    _lib: self.lib.clone(),
+                };
+                res.set_body(http::Body::from_reader(body, None));
+                Ok(res.into())
+            }
+            answer => Ok(answer),
+        }
+    }
+
+    fn link(&mut self, messenger: Rc<dyn Messenger>) {
+        self.vlugin.link(messenger)
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        self.vlugin.context_mut()
+    }
+    fn context(&self) -> &Context {
+        self.vlugin.context()
+    }
+}
+
+/// Body streamed by a native plugin, the library stays loaded until it's read
+struct LoadedBody {
+    // NOTE the body has to go before its library too
+    body: http::Body,
+    _lib: Arc<Library>,
+}
+
+impl Read for LoadedBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+impl BufRead for LoadedBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+/// Locates the library file using the same search path the dynamic linker would
+fn find_library(path: &Path) -> Option<PathBuf> {
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.into());
+    }
+    env::var_os("LD_LIBRARY_PATH")
+        .map(|dirs| env::split_paths(&dirs).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .chain(env::current_dir().ok())
+        .map(|dir| dir.join(path))
+        .find(|path| path.is_file())
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// The dynamic linker would give us back the already loaded library if we
+// opened the same path again so the new version is loaded from a copy
+fn open_fresh_copy(name: &str, source: &Path) -> Result<Library, Box<dyn std::error::Error>> {
+    let copy = env::temp_dir().join(format!(
+        "{}{}{}",
+        name,
+        Uuid::new_v4(),
+        std::env::consts::DLL_SUFFIX
+    ));
+    fs::copy(source, &copy)?;
+    let lib = unsafe { Library::new(&copy) };
+    // the copy is not needed once it's mapped in memory
+    let _ = fs::remove_file(&copy);
+    Ok(lib?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use valor::abi::ffi::vtable;
+
+    static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Default)]
+    struct Probe(Context);
+
+    #[async_trait(?Send)]
+    impl Vlugin for Probe {
+        async fn on_msg(&self, _msg: Message) -> Result<Answer, valor::Error> {
+            Ok(Answer::Pong)
+        }
+
+        async fn on_destroy(&self) -> Result<(), valor::Error> {
+            DESTROYED.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn context_mut(&mut self) -> &mut Context {
+            &mut self.0
+        }
+        fn context(&self) -> &Context {
+            &self.0
+        }
+    }
+
+    #[async_std::test]
+    async fn native_plugins_are_destroyed_when_replaced() -> Result<(), valor::Error> {
+        let vlugin = unsafe { ForeignVlugin::create(vtable::<Probe>(), None) }.await?;
+        let vlugin = NativeVlugin {
+            vlugin: Box::new(vlugin),
+            lib: Arc::new(libloading::os::unix::Library::this().into()),
+        };
+        let runtime = runtime::Runtime::new(()).with_plugin("probe", vlugin)?;
+        runtime.reload_plugin("probe").await?;
+        assert_eq!(DESTROYED.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn failed_reloads_are_retried_when_the_library_changes() {
+        let source = env::temp_dir().join(format!("broken{}", std::env::consts::DLL_SUFFIX));
+        fs::write(&source, "not a library").unwrap();
+        let loader = Loader::default();
+        loader.plugins.borrow_mut().insert(
+            "broken".into(),
+            NativeLib {
+                lib: Arc::new(libloading::os::unix::Library::this().into()),
+                source: Some(source.clone()),
+                modified: None,
+                failed: None,
+            },
+        );
+        let plugin = runtime::VluginDef {
+            r#type: runtime::VluginType::Native {
+                path: Some(source.to_string_lossy().into()),
+            },
+            .."broken".into()
+        };
+        assert_eq!(loader.changed_plugins(), ["broken"]);
+
+        assert!(runtime::Loader::load(&loader, &plugin).await.is_err());
+        assert!(loader.changed_plugins().is_empty());
+
+        let later = modified_time(&source).unwrap() + std::time::Duration::from_secs(1);
+        fs::File::options()
+            .write(true)
+            .open(&source)
+            .and_then(|f| f.set_modified(later))
+            .unwrap();
+        assert_eq!(loader.changed_plugins(), ["broken"]);
+        let _ = fs::remove_file(&source);
+    }
+}
@@ -1,21 +1,47 @@
 //! ValorBin it's the native runtime that is able to load vlugins
 //! from a JSON configuration file and serve incoming HTTP requests.
 
+use access_log::AccessLog;
+use async_signal::{Signal, Signals};
 use async_std::{
-    net::{TcpListener, TcpStream},
+    future,
+    io::{self, BufRead, Read, Write},
+    net::TcpStream,
     stream::StreamExt,
     task,
 };
 use kv_log_macro::{error, info, warn};
+use listen::{Activated, ListenAddr, Listener, TlsAcceptor};
 use loader::Loader;
 use serde::Deserialize;
-use std::{fs::File, path::PathBuf, time::Instant};
+use std::{
+    fs::File,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    rc::Rc,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use structopt::StructOpt;
 use uuid::Uuid;
 use valor::runtime;
 use valor::{http, Vlugin};
 
+mod access_log;
+#[cfg(feature = "http2")]
+mod h2;
+#[cfg(feature = "js")]
+mod js;
+mod listen;
 mod loader;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "wasm")]
+mod wasm;
+#[cfg(feature = "websocket")]
+mod ws;
 
 type Runtime = runtime::Runtime<Loader>;
 
@@ -26,105 +52,399 @@ struct Opt {
     #[structopt(short)]
     with_registry: bool,
 
+    /// Enables the Prometheus metrics endpoint
+    #[structopt(long)]
+    with_metrics: bool,
+
     /// Json file with the list of plugins to load at startup
     #[structopt(short)]
     plugin_file: Option<PathBuf>,
+
+    /// Reloads native plugins when their library changes on disk
+    #[structopt(short, long)]
+    reload: bool,
+
+    /// Address to listen on like `127.0.0.1:8080`, `tls:0.0.0.0:8443` or `unix:/run/valor.sock`,
+    /// it can be given more than once. Sockets passed by the service manager(`LISTEN_FDS`) are
+    /// used as well, without any the server listens on 0.0.0.0:8080
+    #[structopt(short, long)]
+    listen: Vec<ListenAddr>,
+
+    /// PEM file with the default certificate chain for TLS addresses,
+    /// certificates for other hosts can be listed in the plugin file
+    #[cfg(feature = "tls")]
+    #[structopt(long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the default certificate
+    #[cfg(feature = "tls")]
+    #[structopt(long, requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// Seconds to wait for requests in progress to finish when stopping
+    #[structopt(long, default_value = "30")]
+    shutdown_timeout: u64,
+
+    /// Level of the server logs, `error`, `warn`, `info`, `debug` or `trace`
+    #[structopt(long, default_value = "debug")]
+    log_level: femme::LevelFilter,
+
+    #[structopt(flatten)]
+    access_log: access_log::Opt,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 struct ConfigFile {
     plugins: Vec<runtime::VluginDef>,
+    #[cfg(feature = "tls")]
+    #[serde(default)]
+    certificates: Vec<tls::CertDef>,
+    #[serde(default)]
+    access_log: access_log::Config,
 }
 
-#[async_std::main]
-async fn main() {
-    femme::with_level(femme::LevelFilter::Debug);
-    run(Opt::from_args())
-        //.catch_unwind()
-        .await
-        .unwrap_or_else(|e| error!("{}", e));
+fn main() {
+    // read before the runtime starts its threads as it changes the environment
+    let activated = Activated::from_env();
+    let opt = Opt::from_args();
+    femme::with_level(opt.log_level);
+    if let Err(err) = task::block_on(run(opt, activated)) {
+        error!("{}", err);
+        std::process::exit(1);
+    }
 }
 
-async fn run(opt: Opt) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(("0.0.0.0", 8080))
-        .await
-        .expect("Bind address");
-    let addr = format!("http://{}", listener.local_addr()?);
-    info!("listening on {}", addr);
+async fn run(opt: Opt, activated: Activated) -> Result<(), Box<dyn std::error::Error>> {
+    let config: ConfigFile = match &opt.plugin_file {
+        Some(path) => serde_json::from_reader(File::open(path)?)?,
+        None => ConfigFile::default(),
+    };
+    let acceptor = tls_acceptor(&opt, &config)?;
+    let access_log = Arc::new(AccessLog::new(config.access_log, &opt.access_log)?);
+
+    let mut listeners = activated.into_listeners();
+    let default_addr = [listen::DEFAULT_ADDR.parse()?];
+    let addrs = match (&opt.listen[..], &listeners[..]) {
+        ([], []) => &default_addr[..],
+        (addrs, _) => addrs,
+    };
+    for addr in addrs {
+        let listener = Listener::bind(addr, acceptor.as_ref())
+            .await
+            .map_err(|e| format!("can't listen on {:?}: {}", addr, e))?;
+        listeners.push(listener);
+    }
 
-    let mut runtime = Runtime::new(Loader::default()).with_health()?;
+    let loader = Rc::new(Loader::default());
+    let mut runtime = Runtime::new(loader.clone()).with_health()?;
     if opt.with_registry {
         runtime = runtime.with_registry()?;
     }
+    if opt.with_metrics {
+        runtime = runtime.with_metrics()?;
+    }
+
+    for p in config.plugins {
+        runtime
+            .load_plugin(p)
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+    }
+
+    if opt.reload {
+        task::spawn_local(watch_plugins(runtime.clone(), loader));
+    }
+    task::spawn_local(tick_plugins(runtime.clone()));
 
-    if opt.plugin_file.is_some() {
-        let file = File::open(opt.plugin_file.unwrap())?;
-        let config: ConfigFile = serde_json::from_reader(file)?;
-        for p in config.plugins {
-            runtime
-                .load_plugin(p)
-                .await
-                .unwrap_or_else(|err| warn!("{}", err));
+    // every request being handled holds a clone until its response is sent
+    // or its WebSocket is closed
+    let in_flight = Arc::new(());
+    let mut signals = Signals::new([Signal::Term, Signal::Int])?;
+    let serving = listeners
+        .into_iter()
+        .map(|listener| {
+            info!("listening on {}", listener);
+            let (runtime, in_flight) = (runtime.clone(), in_flight.clone());
+            task::spawn_local(serve(listener, runtime, in_flight, access_log.clone()))
+        })
+        .collect::<Vec<_>>();
+    if let Some(signal) = signals.next().await {
+        info!("received {:?}, shutting down", signal?);
+    }
+    for listener in serving {
+        listener.cancel().await;
+    }
+    runtime.close_sockets();
+
+    let timeout = Duration::from_secs(opt.shutdown_timeout);
+    let finished = future::timeout(timeout, requests_done(&in_flight)).await;
+    runtime.shutdown().await;
+    match finished {
+        Ok(_) => {
+            info!("stopped");
+            Ok(())
+        }
+        Err(_) => {
+            let pending = Arc::strong_count(&in_flight) - 1;
+            Err(format!("stopped with {} requests in progress", pending).into())
         }
     }
+}
 
-    let mut incoming = listener.incoming();
-    while let Some(Ok(stream)) = incoming.next().await {
-        let runtime = runtime.clone();
-        task::spawn_local(async move {
-            if let Err(err) = accept(stream, runtime).await {
-                error!("{}", err);
-            }
+#[cfg(feature = "tls")]
+fn tls_acceptor(opt: &Opt, config: &ConfigFile) -> Result<Option<TlsAcceptor>, String> {
+    let default_cert = opt
+        .cert
+        .iter()
+        .zip(&opt.key)
+        .map(|(cert, key)| tls::CertDef {
+            cert: cert.clone(),
+            key: key.clone(),
+            hosts: Vec::new(),
         });
+    let certs = config.certificates.iter().cloned().chain(default_cert);
+    let certs = Arc::new(tls::Certs::load(certs.collect())?);
+    if certs.is_empty() {
+        return Ok(None);
     }
-    Err("Stream closed".into())
+    task::spawn_local(watch_certs(certs.clone()));
+    Ok(Some(certs.acceptor()))
 }
 
-const REQ_ID_HEADER: &str = "x-request-id";
+#[cfg(not(feature = "tls"))]
+fn tls_acceptor(_opt: &Opt, _config: &ConfigFile) -> Result<Option<TlsAcceptor>, String> {
+    Ok(None)
+}
 
-async fn accept(stream: TcpStream, runtime: Runtime) -> Result<(), valor::Error> {
-    async_h1::accept(stream.clone(), |mut req| async {
-        let instant = Instant::now();
-        if req.header(REQ_ID_HEADER).is_none() {
-            let id = Uuid::new_v4().to_string();
-            req.insert_header(REQ_ID_HEADER, id);
+#[cfg(feature = "tls")]
+async fn watch_certs(certs: Arc<tls::Certs>) {
+    loop {
+        task::sleep(RELOAD_INTERVAL).await;
+        certs.reload_changed();
+    }
+}
+
+async fn serve(listener: Listener, runtime: Runtime, in_flight: Arc<()>, log: Arc<AccessLog>) {
+    loop {
+        let (runtime, in_flight, log) = (runtime.clone(), in_flight.clone(), log.clone());
+        let accepted = match &listener {
+            Listener::Tcp(listener) => listener.accept().await.map(|(stream, peer)| {
+                task::spawn_local(async move {
+                    let http2 = is_h2c(&stream).await;
+                    let conn = Conn::new(runtime, in_flight, log, Some(peer));
+                    serve_connection(stream, http2, conn).await
+                });
+            }),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| {
+                let conn = Conn::new(runtime, in_flight, log, None);
+                task::spawn_local(serve_connection(stream, false, conn));
+            }),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, acceptor) => listener.accept().await.map(|(stream, peer)| {
+                let acceptor = acceptor.clone();
+                task::spawn_local(async move {
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            #[cfg(feature = "http2")]
+                            let http2 = stream.get_ref().1.alpn_protocol() == Some(h2::ALPN);
+                            #[cfg(not(feature = "http2"))]
+                            let http2 = false;
+                            let stream = tls::Shared::new(stream);
+                            let conn = Conn::new(runtime, in_flight, log, Some(peer));
+                            serve_connection(stream, http2, conn).await
+                        }
+                        Err(err) => warn!("TLS handshake failed: {}", err),
+                    }
+                });
+            }),
+        };
+        if let Err(err) = accepted {
+            warn!("{}", err);
         }
+    }
+}
 
-        let method = req.method();
-        let path = req.url().path().to_string();
+/// Clients can start HTTP/2 without TLS when they know the server supports it
+#[cfg(feature = "http2")]
+async fn is_h2c(stream: &TcpStream) -> bool {
+    h2::is_preface(stream).await
+}
 
-        let res: http::Response = match runtime.on_msg(req.into()).await {
-            Ok(res) => res.into(),
-            Err(err) => match err {
-                valor::Error::Http(err) => err.status().into(),
-                err => return Err(err.into()),
-            },
-        };
+#[cfg(not(feature = "http2"))]
+async fn is_h2c(_stream: &TcpStream) -> bool {
+    false
+}
 
-        let id = res
-            .header("x-correlation-id")
-            .map(|h| h.as_str())
-            .unwrap_or("-");
-        let plugin = res
-            .header("x-valor-plugin")
-            .map(|h| h.as_str())
-            .unwrap_or("unkown");
-        let status: u16 = res.status().into();
-
-        if !path.starts_with("/_health") {
-            if res.status().is_server_error() {
-                warn!("[{}] {} {} {}", plugin, status, method, path, {
-                    id: id, status: status, dur: instant.elapsed().as_millis() as u64
-                });
-            } else {
-                info!("[{}] {} {} {}", plugin, status, method, path, {
-                    id: id, status: status, dur: instant.elapsed().as_millis() as u64
-                });
+/// What requests of a connection need to be handled
+#[derive(Clone)]
+struct Conn {
+    runtime: Runtime,
+    // idle connections don't count as requests in progress
+    in_flight: Weak<()>,
+    log: Arc<AccessLog>,
+    peer: Option<SocketAddr>,
+}
+
+impl Conn {
+    fn new(
+        runtime: Runtime,
+        in_flight: Arc<()>,
+        log: Arc<AccessLog>,
+        peer: Option<SocketAddr>,
+    ) -> Self {
+        Conn {
+            runtime,
+            in_flight: Arc::downgrade(&in_flight),
+            log,
+            peer,
+        }
+    }
+}
+
+async fn serve_connection<S>(stream: S, http2: bool, conn: Conn)
+where
+    S: Read + Write + Clone + Send + Sync + Unpin + 'static,
+{
+    let served = match http2 {
+        #[cfg(feature = "http2")]
+        true => h2::accept(stream, conn).await,
+        _ => accept(stream, conn).await,
+    };
+    if let Err(err) = served {
+        error!("{}", err);
+    }
+}
+
+async fn requests_done(in_flight: &Arc<()>) {
+    while Arc::strong_count(in_flight) > 1 {
+        task::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+async fn watch_plugins(runtime: Runtime, loader: Rc<Loader>) {
+    loop {
+        task::sleep(RELOAD_INTERVAL).await;
+        for name in loader.changed_plugins() {
+            match runtime.reload_plugin(&name).await {
+                Ok(_) => info!("reloaded {}", name),
+                Err(err) => warn!("{}", err),
             }
         }
+    }
+}
 
-        Ok(res)
+const TICK_INTERVAL: u64 = 60;
+
+// plugin schedules have a granularity of a minute, ticks are sent at the start of every minute
+// without waiting for the previous ones so long running jobs don't delay others
+async fn tick_plugins(runtime: Runtime) {
+    loop {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let next = TICK_INTERVAL - now % TICK_INTERVAL;
+        task::sleep(Duration::from_secs(next)).await;
+        let runtime = runtime.clone();
+        task::spawn_local(async move { runtime.tick(now + next).await });
+    }
+}
+
+const REQ_ID_HEADER: &str = "x-request-id";
+
+async fn accept<S>(stream: S, conn: Conn) -> Result<(), valor::Error>
+where
+    S: Read + Write + Clone + Send + Sync + Unpin + 'static,
+{
+    async_h1::accept(stream, |req| async {
+        let conn = conn.clone();
+        Ok(handle(&conn, req).await)
     })
     .await?;
     Ok(())
 }
+
+/// Dispatches a request of any HTTP version to the runtime, it's logged once the response is sent
+async fn handle(conn: &Conn, mut req: http::Request) -> http::Response {
+    let in_flight = conn.in_flight.upgrade();
+    if req.header(REQ_ID_HEADER).is_none() {
+        let id = Uuid::new_v4().to_string();
+        req.insert_header(REQ_ID_HEADER, id);
+    }
+    req.set_peer_addr(conn.peer);
+    let entry = conn.log.start(&mut req);
+
+    // failures are answered with their status and logged like any other response
+    let mut res: http::Response = match respond(&conn.runtime, req, in_flight.clone()).await {
+        Ok(res) => res,
+        Err(valor::Error::Http(err)) => err.status().into(),
+        Err(err) => {
+            error!("{}", err);
+            http::StatusCode::InternalServerError.into()
+        }
+    };
+    conn.log.finish(entry, &mut res);
+    if let Some(in_flight) = in_flight {
+        InFlight::hold(&mut res, in_flight);
+    }
+    res
+}
+
+/// Upgraded WebSockets hold the request in progress until they are closed
+#[cfg_attr(not(feature = "websocket"), allow(unused_variables))]
+async fn respond(
+    runtime: &Runtime,
+    req: http::Request,
+    in_flight: Option<Arc<()>>,
+) -> Result<http::Response, valor::Error> {
+    #[cfg(feature = "websocket")]
+    if ws::is_upgrade(&req) {
+        return ws::upgrade(runtime, req, in_flight).await;
+    }
+    Ok(runtime.on_msg(req.into()).await?.into())
+}
+
+/// Body that keeps its request in progress until it's sent or the client is gone,
+/// streamed responses don't get cut off by a graceful shutdown
+struct InFlight {
+    body: http::Body,
+    _in_flight: Arc<()>,
+}
+
+impl InFlight {
+    fn hold(res: &mut http::Response, in_flight: Arc<()>) {
+        let len = res.len();
+        let had_type = res.header(http::headers::CONTENT_TYPE).is_some();
+        let body = InFlight {
+            body: res.take_body(),
+            _in_flight: in_flight,
+        };
+        res.set_body(http::Body::from_reader(body, len));
+        if !had_type {
+            res.remove_header(http::headers::CONTENT_TYPE);
+        }
+    }
+}
+
+impl Read for InFlight {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+impl BufRead for InFlight {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
@@ -0,0 +1,179 @@
+//! TLS termination with certificates chosen by the server name the client asks
+//! for(SNI), certificates are read again when their files change on disk.
+use async_std::io::{self, Read, Write};
+use futures_rustls::{
+    rustls::{
+        crypto::ring::{default_provider, sign::any_supported_type},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use kv_log_macro::{info, warn};
+use serde::Deserialize;
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+/// Certificate chain and private key in PEM files for the given hosts,
+/// e.g. `example.com` or `*.example.com`. Without hosts it's the certificate
+/// used when no other matches
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct CertDef {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+struct Cert {
+    def: CertDef,
+    modified: Option<SystemTime>,
+    key: Arc<CertifiedKey>,
+}
+
+impl Cert {
+    fn load(def: CertDef) -> Result<Self, String> {
+        let modified = last_modified(&def);
+        let err = |e: &dyn std::fmt::Display| format!("invalid certificate {:?}: {}", def.cert, e);
+        let mut certs = BufReader::new(File::open(&def.cert).map_err(|e| err(&e))?);
+        let certs = rustls_pemfile::certs(&mut certs)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| err(&e))?;
+        let mut key = BufReader::new(File::open(&def.key).map_err(|e| err(&e))?);
+        let key = rustls_pemfile::private_key(&mut key)
+            .map_err(|e| err(&e))?
+            .ok_or_else(|| err(&"missing private key"))?;
+        let key = any_supported_type(&key).map_err(|e| err(&e))?;
+        Ok(Cert {
+            key: Arc::new(CertifiedKey::new(certs, key)),
+            modified,
+            def,
+        })
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        self.def
+            .hosts
+            .iter()
+            .any(|pattern| match pattern.strip_prefix('*') {
+                Some(domain) => host.ends_with(domain),
+                None => pattern.eq_ignore_ascii_case(host),
+            })
+    }
+}
+
+fn last_modified(def: &CertDef) -> Option<SystemTime> {
+    let cert = fs::metadata(&def.cert).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(&def.key).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+/// Certificates the server can present
+#[derive(Default)]
+pub(crate) struct Certs(RwLock<Vec<Cert>>);
+
+impl Certs {
+    pub fn load(defs: Vec<CertDef>) -> Result<Self, String> {
+        let certs = defs.into_iter().map(Cert::load).collect::<Result<_, _>>()?;
+        Ok(Certs(RwLock::new(certs)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().expect("certs lock").is_empty()
+    }
+
+    /// Loads again the certificates whose files changed, a certificate
+    /// that fails to load is kept until it's fixed
+    pub fn reload_changed(&self) {
+        let mut certs = self.0.write().expect("certs lock");
+        for cert in certs.iter_mut() {
+            if last_modified(&cert.def) == cert.modified {
+                continue;
+            }
+            match Cert::load(cert.def.clone()) {
+                Ok(new) => {
+                    info!("reloaded certificate {:?}", new.def.cert);
+                    *cert = new;
+                }
+                Err(err) => warn!("{}", err),
+            }
+        }
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        #[allow(unused_mut)]
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("supported protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        #[cfg(feature = "http2")]
+        {
+            config.alpn_protocols = vec![crate::h2::ALPN.into(), b"http/1.1".to_vec()];
+        }
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for Certs {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.0.read().ok()?;
+        let host = hello.server_name().map(str::to_ascii_lowercase);
+        host.and_then(|host| certs.iter().find(|cert| cert.matches(&host)))
+            .or_else(|| certs.iter().find(|cert| cert.def.hosts.is_empty()))
+            .map(|cert| cert.key.clone())
+    }
+}
+
+impl std::fmt::Debug for Certs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Certs")
+    }
+}
+
+/// The HTTP server needs a stream it can clone to read and write from different tasks
+pub(crate) struct Shared<S>(Arc<Mutex<S>>);
+
+impl<S> Shared<S> {
+    pub fn new(stream: S) -> Self {
+        Shared(Arc::new(Mutex::new(stream)))
+    }
+}
+
+impl<S> Clone for Shared<S> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+impl<S: Read + Unpin> Read for Shared<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().expect("stream lock")).poll_read(cx, buf)
+    }
+}
+
+impl<S: Write + Unpin> Write for Shared<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().expect("stream lock")).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().expect("stream lock")).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().expect("stream lock")).poll_close(cx)
+    }
+}
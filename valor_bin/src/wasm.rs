@@ -0,0 +1,496 @@
+//! WebAssembly vlugins running in a sandbox with no access to the host.
+//! Modules talk to the runtime using the exports the `vlugin` macro generates
+//! and the message format defined in `valor::abi`, they call back into the runtime
+//! through the `valor.call` import. WASI modules get access
+//! only to the directories and environment variables declared for them.
+use async_std::task;
+use async_trait::async_trait;
+use kv_log_macro::{debug, warn};
+#[cfg(feature = "wasi")]
+use std::collections::BTreeMap;
+use std::{cell::RefCell, rc::Rc};
+use valor::{abi, http, runtime, Answer, Context, Message, Messenger, Vlugin, VluginConfig};
+use wasmtime::{
+    Caller, Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+#[cfg(feature = "wasi")]
+use wasmtime_wasi::{preview1::WasiP1Ctx, DirPerms, FilePerms, WasiCtxBuilder};
+
+/// Instructions a module can run to handle a single call, past it the call is
+/// aborted so a module stuck in a loop doesn't block the server
+const FUEL: u64 = 1_000_000_000;
+
+/// Memory an instance can use, growing past it stops the module
+const MAX_MEMORY: usize = 64 << 20;
+
+/// Engine that meters the instructions modules run
+pub(crate) fn engine() -> Engine {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config).expect("valid engine config")
+}
+
+/// Only modules available locally are supported, e.g. `file:///path/plugin.wasm`
+pub(crate) fn is_local_module(url: &str) -> bool {
+    url.ends_with(".wasm") && (url.starts_with("file://") || !url.contains("://"))
+}
+
+pub(crate) fn load<'a>(
+    engine: &Engine,
+    plugin: &runtime::VluginDef,
+) -> Result<runtime::VluginFactory<'a>, runtime::Error> {
+    let name = plugin.name.clone();
+    let (path, sandbox) = match &plugin.r#type {
+        runtime::VluginType::Web { url } => (url.trim_start_matches("file://"), Sandbox::default()),
+        #[cfg(feature = "wasi")]
+        runtime::VluginType::Wasi {
+            path,
+            preopens,
+            env,
+        } => (
+            path.as_str(),
+            Sandbox {
+                wasi: Some((preopens.clone(), env.clone())),
+            },
+        ),
+        ty => return Err(runtime::Error::VluginNotSupported(ty.to_owned())),
+    };
+    debug!("loading wasm plugin {}({})", name, path);
+    let module = Module::from_file(engine, path).map_err(|e| {
+        warn!("{}", e);
+        runtime::Error::LoadVlugin(name.clone())
+    })?;
+
+    Ok(Box::new(move |cfg| {
+        let module = module.clone();
+        let host = sandbox.host(&name);
+        Box::pin(async move {
+            let vlugin = WasmVlugin::instantiate(&module, host?, cfg)?;
+            Ok(Box::new(vlugin) as Box<dyn Vlugin>)
+        })
+    }))
+}
+
+/// What the module is allowed to access
+#[derive(Default)]
+struct Sandbox {
+    #[cfg(feature = "wasi")]
+    wasi: Option<(BTreeMap<String, runtime::Preopen>, BTreeMap<String, String>)>,
+}
+
+impl Sandbox {
+    #[cfg(not(feature = "wasi"))]
+    fn host(&self, _name: &str) -> Result<Host, valor::Error> {
+        Ok(Host::default())
+    }
+
+    #[cfg(feature = "wasi")]
+    fn host(&self, name: &str) -> Result<Host, valor::Error> {
+        let (preopens, env) = match &self.wasi {
+            Some(wasi) => wasi,
+            None => return Ok(Host::default()),
+        };
+        let mut cx = WasiCtxBuilder::new();
+        cx.inherit_stderr();
+        for (key, value) in env {
+            cx.env(key, value);
+        }
+        for (guest_dir, host_dir) in preopens {
+            let (dir_perms, file_perms) = perms(host_dir);
+            cx.preopened_dir(host_dir.path(), guest_dir, dir_perms, file_perms)
+                .map_err(|e| {
+                    warn!("{} can't open {}: {}", name, host_dir.path(), e);
+                    runtime::Error::InstantiateVlugin(name.into())
+                })?;
+        }
+        Ok(Host {
+            wasi: Some(cx.build_p1()),
+            ..Host::default()
+        })
+    }
+}
+
+/// Directories are read-only unless they are declared writable
+#[cfg(feature = "wasi")]
+fn perms(preopen: &runtime::Preopen) -> (DirPerms, FilePerms) {
+    if preopen.is_writable() {
+        (DirPerms::all(), FilePerms::all())
+    } else {
+        (DirPerms::READ, FilePerms::READ)
+    }
+}
+
+/// Messenger the module calls the runtime with
+struct Linked(Rc<dyn Messenger>);
+
+// WASI requires the state to be `Send` but the store never leaves the
+// thread of the runtime that owns the vlugin
+unsafe impl Send for Linked {}
+
+/// State the host keeps for every module instance
+struct Host {
+    messenger: Option<Linked>,
+    limits: StoreLimits,
+    #[cfg(feature = "wasi")]
+    wasi: Option<WasiP1Ctx>,
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Host {
+            messenger: None,
+            limits: StoreLimitsBuilder::new()
+                .memory_size(MAX_MEMORY)
+                .trap_on_grow_failure(true)
+                .build(),
+            #[cfg(feature = "wasi")]
+            wasi: None,
+        }
+    }
+}
+
+struct WasmVlugin {
+    store: RefCell<Store<Host>>,
+    memory: Memory,
+    alloc: TypedFunc<u32, u32>,
+    free: TypedFunc<(u32, u32), ()>,
+    on_msg: TypedFunc<(u32, u32), u64>,
+    // modules built before the hook existed don't export it
+    destroy: Option<TypedFunc<(u32, u32), u64>>,
+    cx: Context,
+}
+
+impl WasmVlugin {
+    fn instantiate(
+        module: &Module,
+        host: Host,
+        cfg: Option<VluginConfig>,
+    ) -> Result<Self, valor::Error> {
+        // modules can only call the runtime, WASI ones get access to the system as well
+        let mut linker = Linker::new(module.engine());
+        linker
+            .func_wrap("valor", "call", host_call)
+            .map_err(internal_error)?;
+        #[cfg(feature = "wasi")]
+        if host.wasi.is_some() {
+            wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |host: &mut Host| {
+                host.wasi.as_mut().expect("WASI context")
+            })
+            .map_err(internal_error)?;
+        }
+        let mut store = Store::new(module.engine(), host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(FUEL).map_err(internal_error)?;
+        let instance = linker
+            .instantiate(&mut store, module)
+            .map_err(internal_error)?;
+        // the layout of messages is only known for the same version of the format
+        let version = instance
+            .get_typed_func::<(), u32>(&mut store, "valor_abi_version")
+            .and_then(|version| version.call(&mut store, ()))
+            .map_err(|_| internal_error("Module doesn't export its ABI version"))?;
+        if version != abi::VERSION {
+            return Err(internal_error(format!(
+                "Module uses ABI v{} but v{} is required",
+                version,
+                abi::VERSION
+            )));
+        }
+        // WASI reactors need to be initialized before calling other exports
+        if let Ok(init) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            init.call(&mut store, ()).map_err(internal_error)?;
+        }
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| internal_error("Missing memory export"))?;
+        let alloc = instance
+            .get_typed_func(&mut store, "valor_alloc")
+            .map_err(internal_error)?;
+        let free = instance
+            .get_typed_func(&mut store, "valor_free")
+            .map_err(internal_error)?;
+        let create = instance
+            .get_typed_func(&mut store, "valor_create")
+            .map_err(internal_error)?;
+        let on_msg = instance
+            .get_typed_func(&mut store, "valor_on_msg")
+            .map_err(internal_error)?;
+        let destroy = instance.get_typed_func(&mut store, "valor_destroy").ok();
+
+        let vlugin = WasmVlugin {
+            store: RefCell::new(store),
+            memory,
+            alloc,
+            free,
+            on_msg,
+            destroy,
+            cx: Context::default(),
+        };
+        let cfg = cfg
+            .map(|cfg| serde_json::to_vec(&cfg).expect("valid json"))
+            .unwrap_or_default();
+        abi::decode_answer(&vlugin.call(&create, &cfg)?)?;
+        Ok(vlugin)
+    }
+
+    /// Copies the input to the guest memory and reads back the output of `func`
+    fn call(
+        &self,
+        func: &TypedFunc<(u32, u32), u64>,
+        input: &[u8],
+    ) -> Result<Vec<u8>, valor::Error> {
+        // a module messaging itself would have to wait for its own answer
+        let mut store = self
+            .store
+            .try_borrow_mut()
+            .map_err(|_| internal_error("Plugin can't answer while it's waiting on a call"))?;
+        store.set_fuel(FUEL).map_err(internal_error)?;
+        let len = input.len() as u32;
+        let ptr = self.alloc.call(&mut *store, len).map_err(internal_error)?;
+        self.memory
+            .write(&mut *store, ptr as usize, input)
+            .map_err(internal_error)?;
+
+        let out = func.call(&mut *store, (ptr, len)).map_err(internal_error)?;
+        let (ptr, len) = ((out >> 32) as u32, out as u32);
+        let mut output = vec![0; len as usize];
+        self.memory
+            .read(&*store, ptr as usize, &mut output)
+            .map_err(internal_error)?;
+        self.free
+            .call(&mut *store, (ptr, len))
+            .map_err(internal_error)?;
+        Ok(output)
+    }
+}
+
+#[async_trait(?Send)]
+impl Vlugin for WasmVlugin {
+    async fn on_msg(&self, msg: Message) -> Result<Answer, valor::Error> {
+        let msg = abi::encode_message(msg).await?;
+        abi::decode_answer(&self.call(&self.on_msg, &msg)?)
+    }
+
+    async fn on_destroy(&self) -> Result<(), valor::Error> {
+        if let Some(destroy) = &self.destroy {
+            abi::decode_answer(&self.call(destroy, &[])?)?;
+        }
+        Ok(())
+    }
+
+    fn link(&mut self, messenger: Rc<dyn Messenger>) {
+        self.store.get_mut().data_mut().messenger = Some(Linked(messenger));
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        &mut self.cx
+    }
+    fn context(&self) -> &Context {
+        &self.cx
+    }
+}
+
+/// Answers a call of the module with a buffer allocated with its `valor_alloc`,
+/// the module waits for the answer
+fn host_call(mut caller: Caller<'_, Host>, ptr: u32, len: u32) -> wasmtime::Result<u64> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| wasmtime::Error::msg("Missing memory export"))?;
+    let alloc = caller
+        .get_export("valor_alloc")
+        .and_then(|export| export.into_func())
+        .ok_or_else(|| wasmtime::Error::msg("Missing valor_alloc export"))?
+        .typed::<u32, u32>(&caller)?;
+    let mut call = vec![0; len as usize];
+    memory.read(&caller, ptr as usize, &mut call)?;
+
+    let answer = match caller.data().messenger.as_ref().map(|m| m.0.clone()) {
+        Some(messenger) => task::block_on(abi::answer_call(&*messenger, &call)),
+        None => task::block_on(abi::encode_answer(Err(valor::Error::NotSupported))),
+    };
+    let out = alloc.call(&mut caller, answer.len() as u32)?;
+    memory.write(&mut caller, out as usize, &answer)?;
+    Ok((out as u64) << 32 | answer.len() as u64)
+}
+
+fn internal_error(err: impl std::fmt::Display) -> valor::Error {
+    warn!("{}", err);
+    http::Error::from_str(http::StatusCode::InternalServerError, "Plugin crashed").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // valor_create and valor_on_msg of this module never return
+    #[rustfmt::skip]
+    const LOOPING_MODULE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x15, 0x04, // types
+        0x60, 0x01, 0x7f, 0x01, 0x7f, // (i32) -> i32
+        0x60, 0x02, 0x7f, 0x7f, 0x00, // (i32, i32)
+        0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e, // (i32, i32) -> i64
+        0x60, 0x00, 0x01, 0x7f, // () -> i32
+        0x03, 0x05, 0x04, 0x00, 0x01, 0x02, 0x03, // functions
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory
+        0x07, 0x57, 0x06, // exports
+        0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+        0x0b, b'v', b'a', b'l', b'o', b'r', b'_', b'a', b'l', b'l', b'o', b'c', 0x00, 0x00,
+        0x0a, b'v', b'a', b'l', b'o', b'r', b'_', b'f', b'r', b'e', b'e', 0x00, 0x01,
+        0x0c, b'v', b'a', b'l', b'o', b'r', b'_', b'c', b'r', b'e', b'a', b't', b'e', 0x00, 0x02,
+        0x0c, b'v', b'a', b'l', b'o', b'r', b'_', b'o', b'n', b'_', b'm', b's', b'g', 0x00, 0x02,
+        0x11, b'v', b'a', b'l', b'o', b'r', b'_', b'a', b'b', b'i', b'_', b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x00, 0x03,
+        0x0a, 0x18, 0x04, // code
+        0x04, 0x00, 0x41, 0x00, 0x0b, // i32.const 0
+        0x02, 0x00, 0x0b, // nop
+        0x09, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x42, 0x00, 0x0b, // loop br 0 end
+        0x04, 0x00, 0x41, 0x01, 0x0b, // i32.const 1, the ABI version
+    ];
+
+    // valor_on_msg of this module sends a ping to `other` and answers with the answer it gets
+    #[rustfmt::skip]
+    const CALLING_MODULE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x15, 0x04, // types
+        0x60, 0x01, 0x7f, 0x01, 0x7f, // (i32) -> i32
+        0x60, 0x02, 0x7f, 0x7f, 0x00, // (i32, i32)
+        0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e, // (i32, i32) -> i64
+        0x60, 0x00, 0x01, 0x7f, // () -> i32
+        0x02, 0x0e, 0x01, // imports
+        0x05, b'v', b'a', b'l', b'o', b'r', 0x04, b'c', b'a', b'l', b'l', 0x00, 0x02,
+        0x03, 0x06, 0x05, 0x00, 0x01, 0x02, 0x02, 0x03, // functions
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory
+        0x07, 0x57, 0x06, // exports
+        0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+        0x0b, b'v', b'a', b'l', b'o', b'r', b'_', b'a', b'l', b'l', b'o', b'c', 0x00, 0x01,
+        0x0a, b'v', b'a', b'l', b'o', b'r', b'_', b'f', b'r', b'e', b'e', 0x00, 0x02,
+        0x0c, b'v', b'a', b'l', b'o', b'r', b'_', b'c', b'r', b'e', b'a', b't', b'e', 0x00, 0x03,
+        0x0c, b'v', b'a', b'l', b'o', b'r', b'_', b'o', b'n', b'_', b'm', b's', b'g', 0x00, 0x04,
+        0x11, b'v', b'a', b'l', b'o', b'r', b'_', b'a', b'b', b'i', b'_', b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x00, 0x05,
+        0x0a, 0x1d, 0x05, // code
+        0x05, 0x00, 0x41, 0x80, 0x08, 0x0b, // i32.const 1024
+        0x02, 0x00, 0x0b, // nop
+        0x04, 0x00, 0x42, 0x01, 0x0b, // i64.const 1, the pong at 0
+        0x08, 0x00, 0x41, 0x10, 0x41, 0x0f, 0x10, 0x00, 0x0b, // call 0 with the call at 16
+        0x04, 0x00, 0x41, 0x01, 0x0b, // i32.const 1, the ABI version
+        0x0b, 0x1b, 0x02, // data
+        0x00, 0x41, 0x00, 0x0b, 0x01, 0x01, // pong
+        0x00, 0x41, 0x10, 0x0b, 0x0f, // send ping to other
+        0x00, 0x05, 0x00, 0x00, 0x00, b'o', b't', b'h', b'e', b'r', 0x01, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    // valor_create and valor_on_msg of this module grow its memory by 1 GiB and answer with a pong
+    #[rustfmt::skip]
+    const GROWING_MODULE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x15, 0x04, // types
+        0x60, 0x01, 0x7f, 0x01, 0x7f, // (i32) -> i32
+        0x60, 0x02, 0x7f, 0x7f, 0x00, // (i32, i32)
+        0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e, // (i32, i32) -> i64
+        0x60, 0x00, 0x01, 0x7f, // () -> i32
+        0x03, 0x05, 0x04, 0x00, 0x01, 0x02, 0x03, // functions
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory
+        0x07, 0x57, 0x06, // exports
+        0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+        0x0b, b'v', b'a', b'l', b'o', b'r', b'_', b'a', b'l', b'l', b'o', b'c', 0x00, 0x00,
+        0x0a, b'v', b'a', b'l', b'o', b'r', b'_', b'f', b'r', b'e', b'e', 0x00, 0x01,
+        0x0c, b'v', b'a', b'l', b'o', b'r', b'_', b'c', b'r', b'e', b'a', b't', b'e', 0x00, 0x02,
+        0x0c, b'v', b'a', b'l', b'o', b'r', b'_', b'o', b'n', b'_', b'm', b's', b'g', 0x00, 0x02,
+        0x11, b'v', b'a', b'l', b'o', b'r', b'_', b'a', b'b', b'i', b'_', b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x00, 0x03,
+        0x0a, 0x1a, 0x04, // code
+        0x04, 0x00, 0x41, 0x00, 0x0b, // i32.const 0
+        0x02, 0x00, 0x0b, // nop
+        0x0b, 0x00, 0x41, 0x80, 0x80, 0x01, 0x40, 0x00, 0x1a, 0x42, 0x01, 0x0b, // grow 16384 pages
+        0x04, 0x00, 0x41, 0x01, 0x0b, // i32.const 1, the ABI version
+        0x0b, 0x07, 0x01, // data
+        0x00, 0x41, 0x00, 0x0b, 0x01, 0x01, // pong
+    ];
+
+    // valor_create of this module answers with a pong but it's built for ABI v0
+    #[rustfmt::skip]
+    const OUTDATED_MODULE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x15, 0x04, // types
+        0x60, 0x01, 0x7f, 0x01, 0x7f, // (i32) -> i32
+        0x60, 0x02, 0x7f, 0x7f, 0x00, // (i32, i32)
+        0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e, // (i32, i32) -> i64
+        0x60, 0x00, 0x01, 0x7f, // () -> i32
+        0x03, 0x05, 0x04, 0x00, 0x01, 0x02, 0x03, // functions
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory
+        0x07, 0x57, 0x06, // exports
+        0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+        0x0b, b'v', b'a', b'l', b'o', b'r', b'_', b'a', b'l', b'l', b'o', b'c', 0x00, 0x00,
+        0x0a, b'v', b'a', b'l', b'o', b'r', b'_', b'f', b'r', b'e', b'e', 0x00, 0x01,
+        0x0c, b'v', b'a', b'l', b'o', b'r', b'_', b'c', b'r', b'e', b'a', b't', b'e', 0x00, 0x02,
+        0x0c, b'v', b'a', b'l', b'o', b'r', b'_', b'o', b'n', b'_', b'm', b's', b'g', 0x00, 0x02,
+        0x11, b'v', b'a', b'l', b'o', b'r', b'_', b'a', b'b', b'i', b'_', b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x00, 0x03,
+        0x0a, 0x13, 0x04, // code
+        0x04, 0x00, 0x41, 0x00, 0x0b, // i32.const 0
+        0x02, 0x00, 0x0b, // nop
+        0x04, 0x00, 0x42, 0x01, 0x0b, // i64.const 1, the pong at 0
+        0x04, 0x00, 0x41, 0x00, 0x0b, // i32.const 0, the ABI version
+        0x0b, 0x07, 0x01, // data
+        0x00, 0x41, 0x00, 0x0b, 0x01, 0x01, // pong
+    ];
+
+    #[async_std::test]
+    async fn modules_call_the_runtime() -> Result<(), valor::Error> {
+        struct Echo;
+
+        #[async_trait(?Send)]
+        impl Messenger for Echo {
+            async fn send(&self, to: &str, _msg: Message) -> Result<Answer, valor::Error> {
+                Ok(http::Response::from(to).into())
+            }
+            async fn publish(&self, _topic: &str, _payload: serde_json::Value) {}
+            fn socket(&self, _id: &str) -> Option<Rc<dyn valor::Socket>> {
+                None
+            }
+        }
+
+        let module = Module::new(&engine(), CALLING_MODULE).unwrap();
+        let host = Sandbox::default().host("calling")?;
+        let mut vlugin = WasmVlugin::instantiate(&module, host, None)?;
+        vlugin.link(Rc::new(Echo));
+        let mut res: http::Response = vlugin.on_msg(Message::Ping).await?.into();
+        assert_eq!(res.body_string().await?, "other");
+        Ok(())
+    }
+
+    #[test]
+    fn modules_that_loop_forever_are_stopped() {
+        let module = Module::new(&engine(), LOOPING_MODULE).unwrap();
+        let host = Sandbox::default().host("looping").unwrap();
+        assert!(WasmVlugin::instantiate(&module, host, None).is_err());
+    }
+
+    #[test]
+    fn modules_cant_use_too_much_memory() {
+        let module = Module::new(&engine(), GROWING_MODULE).unwrap();
+        let host = Sandbox::default().host("growing").unwrap();
+        assert!(WasmVlugin::instantiate(&module, host, None).is_err());
+    }
+
+    #[test]
+    fn modules_for_another_abi_are_refused() {
+        let module = Module::new(&engine(), OUTDATED_MODULE).unwrap();
+        let host = Sandbox::default().host("outdated").unwrap();
+        assert!(WasmVlugin::instantiate(&module, host, None).is_err());
+    }
+
+    #[cfg(feature = "wasi")]
+    #[test]
+    fn preopens_are_read_only_unless_writable() {
+        let preopens: BTreeMap<String, runtime::Preopen> = serde_json::from_str(
+            r#"{ "/a": "/tmp/a", "/b": { "path": "/tmp/b" }, "/c": { "path": "/tmp/c", "writable": true } }"#,
+        )
+        .unwrap();
+        let perms = |dir| perms(&preopens[dir]);
+        assert_eq!(perms("/a"), (DirPerms::READ, FilePerms::READ));
+        assert_eq!(perms("/b"), (DirPerms::READ, FilePerms::READ));
+        assert_eq!(perms("/c"), (DirPerms::all(), FilePerms::all()));
+        assert_eq!(preopens["/c"].path(), "/tmp/c");
+    }
+}
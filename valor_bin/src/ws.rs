@@ -0,0 +1,135 @@
+//! WebSockets upgraded from HTTP/1.1 requests, the plugin matching the path of
+//! the upgrade request decides whether to accept them and gets their frames.
+use async_std::{
+    channel::{self, Receiver, Sender},
+    task,
+};
+use async_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message as WsMessage},
+    WebSocketStream,
+};
+use futures_util::{SinkExt, StreamExt};
+use kv_log_macro::warn;
+use std::sync::Arc;
+use uuid::Uuid;
+use valor::{
+    http::{self, headers, upgrade::Connection, StatusCode},
+    Answer, Frame, Message, Socket, Vlugin,
+};
+
+/// Checks if the client asks to switch the connection to the WebSocket protocol
+pub(crate) fn is_upgrade(req: &http::Request) -> bool {
+    req.header(headers::UPGRADE)
+        .is_some_and(|upgrade| upgrade.as_str().eq_ignore_ascii_case("websocket"))
+}
+
+/// Asks the plugin matching the request to accept the WebSocket, the connection
+/// is served once the response switching protocols is sent and counts as a request
+/// in progress until it's closed
+pub(crate) async fn upgrade(
+    runtime: &crate::Runtime,
+    req: http::Request,
+    in_flight: Option<Arc<()>>,
+) -> Result<http::Response, valor::Error> {
+    let bad_request = |msg| http::Error::from_str(StatusCode::BadRequest, msg);
+    let key = req
+        .header("sec-websocket-key")
+        .ok_or_else(|| bad_request("Missing WebSocket key"))?
+        .as_str()
+        .to_owned();
+    // clients pick the request id, sockets get one they can't choose
+    let id = Uuid::new_v4().to_string();
+
+    let (outbox, frames) = channel::unbounded();
+    runtime.add_socket(&id, Outbox(outbox.clone()))?;
+    let connect = Message::WsConnect {
+        id: id.clone(),
+        request: req,
+    };
+    match runtime.on_msg(connect).await? {
+        Answer::WsConnect => {}
+        Answer::Http(res) => return Ok(res),
+        _ => return Ok(StatusCode::Forbidden.into()),
+    }
+
+    let mut res = http::Response::new(StatusCode::SwitchingProtocols);
+    res.insert_header(headers::UPGRADE, "websocket");
+    res.insert_header(headers::CONNECTION, "Upgrade");
+    res.insert_header("sec-websocket-accept", derive_accept_key(key.as_bytes()));
+    let upgraded = res.recv_upgrade().await;
+    let runtime = runtime.clone();
+    task::spawn_local(async move {
+        if let Some(conn) = upgraded.await {
+            serve(conn, &id, &runtime, outbox, frames).await;
+        }
+        let _ = runtime.on_msg(Message::WsClose { id }).await;
+        drop(in_flight);
+    });
+    Ok(res)
+}
+
+async fn serve(
+    conn: Connection,
+    id: &str,
+    runtime: &crate::Runtime,
+    outbox: Sender<Option<Frame>>,
+    frames: Receiver<Option<Frame>>,
+) {
+    let ws = WebSocketStream::from_raw_socket(conn, Role::Server, None).await;
+    let (mut sink, mut stream) = ws.split();
+
+    // frames for the client come from plugins at any time, `None` closes the socket
+    let writer = task::spawn_local(async move {
+        while let Ok(frame) = frames.recv().await {
+            let msg = match frame {
+                Some(Frame::Text(text)) => WsMessage::text(text),
+                Some(Frame::Binary(data)) => WsMessage::binary(data),
+                None => break,
+            };
+            if sink.send(msg).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    while let Some(Ok(msg)) = stream.next().await {
+        let frame = match msg {
+            WsMessage::Text(text) => Frame::Text(text.as_str().into()),
+            WsMessage::Binary(data) => Frame::Binary(data.to_vec()),
+            WsMessage::Close(_) => break,
+            // pings are answered by the protocol
+            _ => continue,
+        };
+        let msg = Message::WsFrame {
+            id: id.into(),
+            frame,
+        };
+        let reply = match runtime.on_msg(msg).await {
+            Ok(Answer::WsFrame(frame)) => Some(frame),
+            Ok(Answer::WsClose) => None,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("{}", err, { id: id });
+                continue;
+            }
+        };
+        let _ = outbox.send(reply).await;
+    }
+    writer.cancel().await;
+}
+
+/// Lets plugins queue frames for the client
+struct Outbox(Sender<Option<Frame>>);
+
+impl Socket for Outbox {
+    fn send(&self, frame: Frame) -> Result<(), valor::Error> {
+        self.0
+            .try_send(Some(frame))
+            .map_err(|_| http::Error::from_str(StatusCode::Gone, "Socket closed").into())
+    }
+
+    fn close(&self) {
+        let _ = self.0.try_send(None);
+    }
+}
@@ -1,14 +1,22 @@
 //! Valor "vlugin" is a macro that creates a struct implementing the
 //! Vlugin trait using
+//!
+//! Routes handled by the plugin can be declared as part of its metadata
+//! with `#[vlugin(route = "/", route = "/:id")]`, like in the plugin definition
+//! they are relative to the prefix the plugin is mounted on.
 
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 use quote::quote;
-use syn::{Error, ItemFn};
+use syn::{parse_macro_input, AttributeArgs, Error, ItemFn, Lit, Meta, NestedMeta};
 
 /// vlugin
 #[proc_macro_attribute]
-pub fn vlugin(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn vlugin(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let routes = match routes(parse_macro_input!(attr as AttributeArgs)) {
+        Ok(routes) => routes.join("\n"),
+        Err(err) => return err.to_compile_error().into(),
+    };
     let item: proc_macro2::TokenStream = item.into();
 
     if let Ok(func) = syn::parse2::<ItemFn>(item.clone()) {
@@ -48,15 +56,59 @@ pub fn vlugin(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
             #[cfg(not(target_arch = "wasm32"))]
             #[no_mangle]
-            pub extern "Rust" fn instantiate_vlugin(cfg: Option<valor::VluginConfig>) ->
-                core::pin::Pin<Box<dyn core::future::Future<
-                    Output = core::result::Result<Box<dyn valor::Vlugin>, valor::Error>
-                >>>
-            {
-                Box::pin(async {
-                    let instance = v::Vlugin::create(cfg).await?;
-                    Ok(Box::new(instance) as Box<dyn valor::Vlugin>)
-                })
+            pub static VALOR_ABI_VERSION: u32 = valor::abi::ffi::ABI_VERSION;
+
+            #[cfg(not(target_arch = "wasm32"))]
+            #[no_mangle]
+            pub extern "C" fn valor_metadata() -> valor::abi::ffi::Metadata {
+                valor::abi::ffi::Metadata::new(
+                    v::RUSTC_VERSION,
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION"),
+                    #routes,
+                )
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            #[no_mangle]
+            pub extern "C" fn valor_vlugin() -> valor::abi::ffi::VluginVTable {
+                valor::abi::ffi::vtable::<v::Vlugin>()
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            #[no_mangle]
+            pub extern "C" fn valor_abi_version() -> u32 {
+                valor::abi::VERSION
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            #[no_mangle]
+            pub extern "C" fn valor_alloc(len: u32) -> u32 {
+                valor::abi::guest::alloc(len)
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            #[no_mangle]
+            pub unsafe extern "C" fn valor_free(ptr: u32, len: u32) {
+                valor::abi::guest::free(ptr, len)
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            #[no_mangle]
+            pub unsafe extern "C" fn valor_create(ptr: u32, len: u32) -> u64 {
+                valor::abi::guest::create::<v::Vlugin>(ptr, len)
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            #[no_mangle]
+            pub unsafe extern "C" fn valor_on_msg(ptr: u32, len: u32) -> u64 {
+                valor::abi::guest::on_msg(ptr, len)
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            #[no_mangle]
+            pub unsafe extern "C" fn valor_destroy(ptr: u32, len: u32) -> u64 {
+                valor::abi::guest::destroy(ptr, len)
             }
 
             #item
@@ -71,3 +123,15 @@ pub fn vlugin(_attr: TokenStream, item: TokenStream) -> TokenStream {
         .into()
     }
 }
+
+fn routes(args: AttributeArgs) -> Result<Vec<String>, Error> {
+    args.into_iter()
+        .map(|arg| match arg {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("route") => match nv.lit {
+                Lit::Str(route) => Ok(route.value()),
+                lit => Err(Error::new_spanned(lit, "Route should be a string")),
+            },
+            arg => Err(Error::new_spanned(arg, "Expected `route = \"/path\"`")),
+        })
+        .collect()
+}
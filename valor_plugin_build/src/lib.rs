@@ -1,9 +1,11 @@
+use proc_macro2::TokenStream;
 use quote::{quote, ToTokens};
 use std::{
     env,
     fs::{self, File},
     io::Read,
     path::Path,
+    process::Command,
 };
 use syn::{parse_quote, ReturnType};
 
@@ -51,6 +53,24 @@ pub fn build() {
         })
         .unwrap_or_else(|| parse_quote!(Ok(())));
 
+    let on_destroy = pub_fns
+        .iter()
+        .find_map(|f| {
+            f.sig.ident.eq("on_destroy").then(|| {
+                let destroy_res = as_result(f.sig.output.clone(), quote!(()));
+                let destroy_args = if f.sig.inputs.is_empty() {
+                    quote!()
+                } else {
+                    quote!(self.context())
+                };
+                quote! {
+                    let res = crate::on_destroy(#destroy_args).await;
+                    #destroy_res
+                }
+            })
+        })
+        .unwrap_or_else(|| parse_quote!(Ok(())));
+
     let on_request = pub_fns
         .iter()
         .find(|f| &f.sig.ident == "on_request")
@@ -63,7 +83,67 @@ pub fn build() {
         quote!(req.into())
     };
 
+    // optional handlers of other kind of messages that get the context as first argument
+    // if they take more arguments than the message has
+    let handler_arm = |name: &str, pattern: TokenStream, args: TokenStream, arity: usize| {
+        let handler = match pub_fns.iter().find(|f| f.sig.ident == name) {
+            Some(handler) => handler,
+            None => return quote!(#[allow(unused_variables)] #pattern => Ok(valor::Answer::Pong),),
+        };
+        let ident = &handler.sig.ident;
+        let res = as_result(handler.sig.output.clone(), quote!(valor::Answer::Pong));
+        let args = if handler.sig.inputs.len() > arity {
+            quote!(self.context(), #args)
+        } else {
+            args
+        };
+        quote! {
+            #pattern => {
+                let res = crate::#ident(#args).await;
+                #res.map(|res| valor::Answer::from(res))
+            }
+        }
+    };
+    let on_event = handler_arm(
+        "on_event",
+        quote!(valor::Message::Event { topic, payload }),
+        quote!(topic, payload),
+        2,
+    );
+    let on_tick = handler_arm(
+        "on_tick",
+        quote!(valor::Message::Tick(timestamp)),
+        quote!(timestamp),
+        1,
+    );
+    // WebSockets are refused unless there's a connect handler to accept them
+    let on_connect = handler_arm(
+        "on_connect",
+        quote!(valor::Message::WsConnect { id, request }),
+        quote!(id, request),
+        2,
+    );
+    let on_frame = handler_arm(
+        "on_frame",
+        quote!(valor::Message::WsFrame { id, frame }),
+        quote!(id, frame),
+        2,
+    );
+    let on_close = handler_arm(
+        "on_close",
+        quote!(valor::Message::WsClose { id }),
+        quote!(id),
+        1,
+    );
+    // plugins are healthy unless their health handler fails
+    let on_health = handler_arm("on_health", quote!(valor::Message::Health), quote!(), 0);
+
+    let rustc_version = rustc_version();
+
     let module = quote! {
+        /// Compiler that built the plugin, runtimes can use it to spot incompatible builds
+        pub const RUSTC_VERSION: &str = #rustc_version;
+
         #[derive(Default)]
         pub struct Vlugin(valor::Context);
 
@@ -73,11 +153,28 @@ pub fn build() {
                 #on_create
             }
 
+            async fn on_destroy(&self) -> core::result::Result<(), valor::Error> {
+                #on_destroy
+            }
+
             async fn on_msg(&self, req: valor::Message) ->
                 core::result::Result<valor::Answer, valor::Error>
             {
-                let res = crate::on_request(#req_args).await;
-                #req_result.map(|res| valor::Answer::from(res))
+                match req {
+                    #on_event
+                    #on_tick
+                    #on_connect
+                    #on_frame
+                    #on_close
+                    #on_health
+                    // handlers can take the request as any type it converts into
+                    #[allow(clippy::useless_conversion)]
+                    valor::Message::Http(req) => {
+                        let res = crate::on_request(#req_args).await;
+                        #req_result.map(|res| valor::Answer::from(res))
+                    }
+                    valor::Message::Ping => Ok(valor::Answer::Pong),
+                }
             }
 
             fn context_mut(&mut self) -> &mut valor::Context {
@@ -93,3 +190,14 @@ pub fn build() {
     let dest_path = Path::new(&out_dir).join("vlugin.rs");
     fs::write(&dest_path, module.to_string()).unwrap();
 }
+
+fn rustc_version() -> String {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|v| v.trim().to_owned())
+        .unwrap_or_else(|| "unknown".into())
+}
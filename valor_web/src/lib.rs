@@ -1,4 +1,6 @@
 //! Valor web
+use futures_lite::AsyncReadExt;
+use js_sys::{Object, Reflect, Uint8Array};
 use loader::Loader;
 use std::rc::Rc;
 use valor::{web::into_request, Vlugin};
@@ -8,6 +10,8 @@ use web_sys::{window, BroadcastChannel, MessageEvent, RequestInit};
 
 mod loader;
 
+const CHUNK_SIZE: usize = 8 * 1024;
+
 #[wasm_bindgen]
 extern "C" {
     type TransferredRequest;
@@ -50,9 +54,7 @@ pub async fn run() -> Result<(), JsValue> {
             if !status.is_success() {
                 log::warn!("{:?}", res);
             }
-            let res = transferable_response(res).await;
-            log::debug!("posting res: {:?}", res);
-            responses.post_message(&res).expect("response");
+            post_response(res, &responses).await;
         });
     }) as Box<dyn Fn(MessageEvent)>);
     req_channel.set_onmessage(Some(on_msg.as_ref().unchecked_ref()));
@@ -61,26 +63,57 @@ pub async fn run() -> Result<(), JsValue> {
     Ok(())
 }
 
-async fn transferable_response(mut res: valor::Response) -> JsValue {
-    let body = res.body_bytes().await.unwrap_or_default();
-    let body = js_sys::Uint8Array::from(body.as_slice()).buffer();
-    let headers = js_sys::Object::new();
+/// Sends the response to the service worker, bodies of unknown length are
+/// posted a chunk at a time after the response until a message marks their end
+async fn post_response(mut res: valor::Response, responses: &BroadcastChannel) {
+    let headers = Object::new();
     for (name, value) in res.iter() {
-        js_sys::Reflect::set(
-            &headers,
-            &JsValue::from(name.as_str()),
-            &JsValue::from(value.as_str()),
-        )
-        .unwrap();
+        set(&headers, name.as_str(), value.as_str());
+    }
+    let init = Object::new();
+    set(&init, "status", res.status() as u16);
+    set(&init, "headers", headers);
+    let msg = Object::new();
+    set(&msg, "init", init);
+
+    if res.len().is_some() {
+        let body = res.body_bytes().await.unwrap_or_default();
+        set(&msg, "body", Uint8Array::from(body.as_slice()).buffer());
+        log::debug!("posting res: {:?}", msg);
+        responses.post_message(&msg).expect("response");
+        return;
+    }
+
+    let id = res
+        .header("x-correlation-id")
+        .map(|id| id.as_str().to_owned())
+        .unwrap_or_default();
+    set(&msg, "stream", true);
+    log::debug!("posting streamed res: {:?}", msg);
+    responses.post_message(&msg).expect("response");
+
+    let mut body = res.take_body();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let msg = Object::new();
+        set(&msg, "id", id.as_str());
+        // errors reading the body can only end it
+        match body.read(&mut chunk).await.unwrap_or(0) {
+            0 => {
+                set(&msg, "done", true);
+                responses.post_message(&msg).expect("response");
+                return;
+            }
+            n => {
+                set(&msg, "chunk", Uint8Array::from(&chunk[..n]).buffer());
+                responses.post_message(&msg).expect("response");
+            }
+        }
     }
-    let status = res.status() as u16;
-    let init = js_sys::Object::new();
-    js_sys::Reflect::set(&init, &JsValue::from("status"), &status.into()).unwrap();
-    js_sys::Reflect::set(&init, &JsValue::from("headers"), &headers.into()).unwrap();
-    let res = js_sys::Object::new();
-    js_sys::Reflect::set(&res, &JsValue::from("body"), &body.into()).unwrap();
-    js_sys::Reflect::set(&res, &JsValue::from("init"), &init.into()).unwrap();
-    res.into()
+}
+
+fn set(obj: &Object, key: &str, value: impl Into<JsValue>) {
+    Reflect::set(obj, &JsValue::from(key), &value.into()).unwrap();
 }
 
 fn load_service_worker(url: &str) -> Result<(), JsValue> {
@@ -55,9 +55,16 @@ async function broadcastAndWaitResponse(req) {
 const reqChan = new BroadcastChannel("req_channel");
 const resChan = new BroadcastChannel("res_channel");
 const pendingRequests = new Map();
+// controllers of streamed response bodies that are still open
+const streams = new Map();
 
 resChan.onmessage = ({ data = {} }) => {
-  const { body, init } = data;
+  if (data.id) {
+    streamChunk(data);
+    return;
+  }
+
+  const { body, init, stream } = data;
   const id = init.headers["x-correlation-id"];
   if (!id) {
     console.debug("request without id");
@@ -70,9 +77,29 @@ resChan.onmessage = ({ data = {} }) => {
     return;
   }
 
+  if (stream) {
+    const body = new ReadableStream({
+      start: (controller) => streams.set(id, controller),
+      cancel: () => streams.delete(id),
+    });
+    resolve(new Response(body, init));
+    return;
+  }
   resolve(new Response(body, init));
 };
 
+// chunks of a streamed body follow its response until one marks the end
+function streamChunk({ id, chunk, done }) {
+  const controller = streams.get(id);
+  if (!controller) return;
+  if (done) {
+    streams.delete(id);
+    controller.close();
+  } else {
+    controller.enqueue(new Uint8Array(chunk));
+  }
+}
+
 function uuidv4() {
   return ([1e7] + -1e3 + -4e3 + -8e3 + -1e11).replace(
     /[018]/g,